@@ -1,51 +1,1246 @@
-use serde::{Deserialize, Serialize};
-use anyhow::Result;
+use serde::de::{DeserializeOwned, Error as _};
+use serde::{Deserialize, Deserializer, Serialize};
+use anyhow::{bail, Result};
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::IpAddr;
+use crate::crypto::random_string;
 use crate::ActionParams;
 
+/// The newest `manifest_version` whose `[setup]` section can be read
+const MAX_MANIFEST_VERSION: i64 = 3;
+
+/// Length of generated values when the item does not set one
+const DEFAULT_GENERATED_LENGTH: usize = 32;
+
+/// Longest value that may be generated
+const MAX_GENERATED_LENGTH: usize = 1024;
+
 impl DeployConfigSpec {
   pub fn from_toml(manifest: &str) -> Result<DeployConfigSpec> {
     let manifest: Manifest = toml::from_str(manifest)?;
-    Ok(manifest.setup.unwrap_or(DeployConfigSpec {
+
+    // Manifests written before versioning was introduced have no manifest_version
+    let version = match &manifest.manifest_version {
+      None => 1,
+      Some(toml::Value::Integer(version)) => *version,
+      Some(toml::Value::String(version)) => match version.parse() {
+        Ok(version) => version,
+        Err(_) => bail!("manifest_version {:?} is not a number", version),
+      },
+      Some(version) => bail!("manifest_version {} is not a number", version),
+    };
+    if !(1..=MAX_MANIFEST_VERSION).contains(&version) {
+      bail!(
+        "manifest_version {} is not supported. Quick Deploy supports versions 1 to {}.",
+        version,
+        MAX_MANIFEST_VERSION
+      );
+    }
+
+    let mut spec = manifest.setup.unwrap_or(DeployConfigSpec {
       backends: vec![],
-      dictionaries: vec![]
-    }))
+      dictionaries: vec![],
+      config_stores: BTreeMap::new(),
+      kv_stores: BTreeMap::new(),
+      secret_stores: BTreeMap::new(),
+      log_endpoints: BTreeMap::new(),
+    });
+
+    // The fastly CLI prompts for every backend with a description
+    for backend in spec.backends.iter_mut() {
+      if backend.prompt.is_none() {
+        backend.prompt = backend.description.to_owned();
+      }
+    }
+
+    // Resources declared in arrays of tables must name themselves
+    check_names("backend", "name", spec.backends.iter().map(|backend| backend.name.as_str()))?;
+    check_names("dictionary", "name", spec.dictionaries.iter().map(|dict| dict.name.as_str()))?;
+    for dict in &spec.dictionaries {
+      let kind = format!("item of dictionary {}", dict.name);
+      check_names(&kind, "key", dict.items.iter().map(|item| item.key.as_str()))?;
+    }
+
+    // Items of keyed tables take their key from the table name
+    for store in spec.config_stores.values_mut() {
+      for (key, item) in store.items.iter_mut() {
+        item.key = key.to_owned();
+      }
+    }
+    for (name, store) in &spec.kv_stores {
+      let kind = format!("item of KV store {}", name);
+      check_names(&kind, "key", store.items.iter().map(|item| item.key.as_str()))?;
+      for item in &store.items {
+        if item.value.is_some() == item.file.is_some() {
+          bail!("Item {} of KV store {} must set either a value or a file", item.key, name);
+        }
+      }
+    }
+    for (name, store) in spec.secret_stores.iter_mut() {
+      let kind = format!("entry of secret store {}", name);
+      check_names(&kind, "key", store.entries.iter().map(|entry| entry.key.as_str()))?;
+      for entry in store.entries.iter_mut() {
+        if entry.input_type != InputType::Generated {
+          entry.input_type = InputType::Password;
+        }
+      }
+    }
+    spec.check_variables()?;
+
+    for (_, item) in spec.inputs() {
+      if item.input_type == InputType::Generated {
+        let length = item.length.unwrap_or(DEFAULT_GENERATED_LENGTH);
+        if length == 0 || length > MAX_GENERATED_LENGTH {
+          bail!("The length of {} must be between 1 and {}", item.label(), MAX_GENERATED_LENGTH);
+        }
+        if item.alphabet().is_empty() {
+          bail!("The alphabet of {} must not be empty", item.label());
+        }
+      } else if item.github_secret.is_some() {
+        bail!("{} sets github_secret, which is only supported for generated values", item.label());
+      }
+    }
+
+    for (name, endpoint) in spec.log_endpoints.iter_mut() {
+      for (key, field) in endpoint.fields.iter_mut() {
+        field.key = key.to_owned();
+      }
+      for required in endpoint.provider.required_fields() {
+        if !endpoint.fields.contains_key(*required) {
+          bail!("Log endpoint {} is missing the {} field", name, required);
+        }
+      }
+    }
+
+    Ok(spec)
+  }
+
+  /// Checks that every template value only refers to variables that exist.
+  fn check_variables(&self) -> Result<()> {
+    for backend in &self.backends {
+      check_references(&backend.address)?;
+      for value in backend.hostnames() {
+        check_references(value)?;
+      }
+    }
+    for (_, item) in self.inputs() {
+      if let Some(default) = &item.value {
+        check_references(default)?;
+      }
+    }
+    for store in self.kv_stores.values() {
+      for item in &store.items {
+        if let Some(value) = &item.value {
+          check_references(value)?;
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Lists every value the user is asked for, with the name of its deploy form field.
+  pub fn inputs(&self) -> Vec<(String, &DictionaryItemSpec)> {
+    let mut inputs = vec![];
+
+    for dict in &self.dictionaries {
+      for item in &dict.items {
+        inputs.push((dict.field_name(item), item));
+      }
+    }
+    for (name, store) in &self.config_stores {
+      for item in store.items.values() {
+        inputs.push((keyed_field("config_store", name, item), item));
+      }
+    }
+    for (name, store) in &self.secret_stores {
+      for entry in &store.entries {
+        inputs.push((keyed_field("secret_store", name, entry), entry));
+      }
+    }
+    for (name, endpoint) in &self.log_endpoints {
+      for field in endpoint.fields.values() {
+        inputs.push((keyed_field("log_endpoint", name, field), field));
+      }
+    }
+
+    inputs
+  }
+}
+
+/// Checks that every resource of a kind has a name, and that no two share one.
+fn check_names<'a>(kind: &str, label: &str, names: impl Iterator<Item = &'a str>) -> Result<()> {
+  let mut seen = BTreeSet::new();
+  for name in names {
+    if name.is_empty() {
+      bail!("Every {} must have a {}", kind, label);
+    }
+    if !seen.insert(name) {
+      bail!("More than one {} has the {} {}", kind, label, name);
+    }
   }
+
+  Ok(())
 }
 
 #[derive(Deserialize)]
 pub struct Manifest {
+  pub manifest_version: Option<toml::Value>,
   pub setup: Option<DeployConfigSpec>
 }
 
+/// A resource that can be declared either in an array of tables, e.g. `[[setup.backends]]`
+/// with a `name` field, or as a table keyed by its name, e.g. `[setup.backends.origin]`.
+pub trait Named {
+  fn set_name(&mut self, name: String);
+}
+
+/// Reads either layout of a named resource into a list.
+fn named_tables<'de, D, T>(deserializer: D) -> std::result::Result<Vec<T>, D::Error>
+where
+  D: Deserializer<'de>,
+  T: DeserializeOwned + Named,
+{
+  match toml::Value::deserialize(deserializer)? {
+    toml::Value::Array(tables) => tables
+      .into_iter()
+      .map(|table| table.try_into().map_err(D::Error::custom))
+      .collect(),
+    toml::Value::Table(tables) => tables
+      .into_iter()
+      .map(|(name, table)| {
+        let mut resource: T = table
+          .try_into()
+          .map_err(|err| D::Error::custom(format!("{}: {}", name, err)))?;
+        resource.set_name(name);
+        Ok(resource)
+      })
+      .collect(),
+    _ => Err(D::Error::custom("expected an array of tables or a table keyed by name")),
+  }
+}
+
 pub struct DeployConfig {
   pub spec: DeployConfigSpec,
-  pub params: ActionParams
+  pub params: ActionParams,
+  pub variables: Variables,
+  /// Values of `generated` items, keyed by field name
+  pub generated: BTreeMap<String, String>,
+}
+
+/// The variables that template values can refer to as `${name}`
+const VARIABLES: &[&str] = &["service_id", "domain", "github.login", "github.repository"];
+
+/// Facts about the deployment that template values can refer to. Each variable gets its value
+/// once the deployment has progressed far enough for it to be known.
+#[derive(Default)]
+pub struct Variables {
+  values: BTreeMap<&'static str, String>,
+}
+
+impl Variables {
+  pub fn set(&mut self, name: &'static str, value: &str) {
+    self.values.insert(name, value.to_owned());
+  }
+
+  /// Whether every variable a value refers to is known yet
+  pub fn is_ready(&self, template: &str) -> bool {
+    match references(template) {
+      Ok(references) => references
+        .iter()
+        .all(|(_, name)| self.values.contains_key(name)),
+      Err(_) => false,
+    }
+  }
+
+  /// Replaces every `${name}` in a value with the variable's value.
+  pub fn interpolate(&self, template: &str) -> Result<String> {
+    let mut output = String::new();
+    let mut copied = 0;
+
+    for (range, name) in references(template)? {
+      let value = match self.values.get(name) {
+        Some(value) => value,
+        None => bail!("${{{}}} is not known yet, so {:?} cannot be used", name, template),
+      };
+      output.push_str(&template[copied..range.start]);
+      output.push_str(value);
+      copied = range.end;
+    }
+
+    output.push_str(&template[copied..]);
+    Ok(output)
+  }
+}
+
+/// Finds the `${name}` references in a value, with their positions.
+fn references(template: &str) -> Result<Vec<(std::ops::Range<usize>, &str)>> {
+  let mut references = vec![];
+  let mut offset = 0;
+
+  while let Some(start) = template[offset..].find("${").map(|start| offset + start) {
+    let end = match template[start..].find('}') {
+      Some(end) => start + end + 1,
+      None => bail!("{:?} has a ${{ without a closing }}", template),
+    };
+    references.push((start..end, template[start + 2..end - 1].trim()));
+    offset = end;
+  }
+
+  Ok(references)
+}
+
+/// Whether a value refers to any variables
+pub fn uses_variables(template: &str) -> bool {
+  references(template).is_ok_and(|references| !references.is_empty())
+}
+
+/// Checks that a value only refers to variables that exist.
+fn check_references(template: &str) -> Result<()> {
+  for (_, name) in references(template)? {
+    if !VARIABLES.contains(&name) {
+      bail!(
+        "{:?} refers to an unknown variable ${{{}}}. The available variables are {}.",
+        template,
+        name,
+        VARIABLES
+          .iter()
+          .map(|name| format!("${{{}}}", name))
+          .collect::<Vec<_>>()
+          .join(", ")
+      );
+    }
+  }
+
+  Ok(())
+}
+
+impl DeployConfig {
+  /// Applies the values entered on the deploy form and checks every one of them, so that all
+  /// problems can be reported together before any resources are created.
+  ///
+  /// Prompted backends take their address and port from `backend.<name>.address` and
+  /// `backend.<name>.port` params. Backends without a prompt always use the template's values.
+  /// Only the template's values may refer to variables; values entered by the user are used as
+  /// they are.
+  pub fn validate(&mut self) -> Result<(), ValidationError> {
+    let mut errors = vec![];
+
+    for backend in self.spec.backends.iter_mut().filter(|backend| backend.prompt.is_some()) {
+      let field = backend.address_field();
+      // A blank address keeps the template's, which may refer to variables
+      let address = self.params.get(&field).map(|address| address.trim());
+      if let Some(address) = address.filter(|address| !address.is_empty()) {
+        if is_valid_host(address) {
+          backend.address = address.to_string();
+        } else {
+          errors.push(FieldError::new(field, "Enter a hostname or IP address, without a scheme or path"));
+        }
+      }
+
+      let field = backend.port_field();
+      if let Some(port) = self.params.get(&field) {
+        let port = port.trim();
+        if !port.is_empty() {
+          match port.parse::<u16>() {
+            Ok(port) if port > 0 => backend.port = Some(port.into()),
+            _ => errors.push(FieldError::new(field, "Enter a port between 1 and 65535")),
+          }
+        }
+      }
+    }
+
+    // Generated values are never entered on the form
+    for (field, item) in self.spec.inputs().into_iter().filter(|(_, item)| item.input_type != InputType::Generated) {
+      // Defaults that refer to facts not known yet are checked once they can be resolved
+      if self.param(&field).is_none() {
+        if let Some(default) = &item.value {
+          if !self.variables.is_ready(default) {
+            continue;
+          }
+        }
+      }
+
+      if let Err(err) = self.item_value(&field, item) {
+        errors.push(FieldError::new(field, &err.to_string()));
+      }
+    }
+
+    if errors.is_empty() {
+      Ok(())
+    } else {
+      Err(ValidationError { errors })
+    }
+  }
+
+  /// Replaces variables in backend addresses and hostnames, now that their values are known.
+  pub fn resolve_backends(&mut self) -> Result<()> {
+    for backend in self.spec.backends.iter_mut() {
+      backend.address = self.variables.interpolate(&backend.address)?;
+      if !is_valid_host(&backend.address) {
+        bail!("The address of backend {} is not a hostname or IP address: {:?}", backend.name, backend.address);
+      }
+
+      for value in backend.hostnames_mut() {
+        *value = self.variables.interpolate(value)?;
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Resolves the values of a config store's items.
+  pub fn config_store_items(&self, name: &str, store: &ConfigStoreSpec) -> Result<Vec<(String, String)>> {
+    self.keyed_values("config_store", name, &store.items)
+  }
+
+  /// Resolves the settings of a logging endpoint.
+  pub fn log_endpoint_fields(&self, name: &str, endpoint: &LogEndpointSpec) -> Result<Vec<(String, String)>> {
+    self.keyed_values("log_endpoint", name, &endpoint.fields)
+  }
+
+  fn keyed_values(
+    &self,
+    prefix: &str,
+    name: &str,
+    items: &BTreeMap<String, DictionaryItemSpec>,
+  ) -> Result<Vec<(String, String)>> {
+    items
+      .values()
+      .map(|item| Ok((item.key.to_owned(), self.item_value(&keyed_field(prefix, name, item), item)?)))
+      .collect()
+  }
+
+  /// Resolves the contents of every secret store, keyed by store name. This includes the
+  /// `password` items of dictionaries that name a secret store.
+  pub fn secret_store_entries(&self) -> Result<BTreeMap<String, Vec<(String, String)>>> {
+    let mut stores: BTreeMap<String, Vec<(String, String)>> = BTreeMap::new();
+
+    for (name, store) in &self.spec.secret_stores {
+      let entries = stores.entry(name.to_owned()).or_default();
+      for entry in &store.entries {
+        entries.push((entry.key.to_owned(), self.item_value(&keyed_field("secret_store", name, entry), entry)?));
+      }
+    }
+
+    for dict in &self.spec.dictionaries {
+      if let Some(name) = &dict.secret_store {
+        let entries = stores.entry(name.to_owned()).or_default();
+        for item in dict.items.iter().filter(|item| dict.is_secret(item)) {
+          entries.push((item.key.to_owned(), self.dictionary_item_value(dict, item)?));
+        }
+      }
+    }
+
+    Ok(stores)
+  }
+
+  /// Resolves the value of a dictionary item from the `dict.<name>.<key>` param.
+  pub fn dictionary_item_value(&self, dict: &DictionarySpec, item: &DictionaryItemSpec) -> Result<String> {
+    self.item_value(&dict.field_name(item), item)
+  }
+
+  /// Resolves the value entered for an item, falling back to the template's default when nothing
+  /// was entered. Variables in the default are replaced with their values, while entered values
+  /// are used as they are.
+  pub fn item_value(&self, field: &str, item: &DictionaryItemSpec) -> Result<String> {
+    if item.input_type == InputType::Generated {
+      return match self.generated.get(field) {
+        Some(value) => Ok(value.to_owned()),
+        None => bail!("No value has been generated for {}", item.label()),
+      };
+    }
+
+    let value = match (self.param(field), &item.value) {
+      (Some(value), _) => value.to_string(),
+      // Unchecked checkboxes are not submitted at all
+      (None, _) if item.input_type == InputType::Boolean => "false".to_string(),
+      (None, Some(default)) => self.variables.interpolate(default)?,
+      (None, None) => bail!("No value provided for {}", item.label()),
+    };
+
+    item.validate(&value)?;
+    Ok(value)
+  }
+
+  /// The value entered for a field, if it is not blank
+  fn param(&self, field: &str) -> Option<&str> {
+    self
+      .params
+      .get(field)
+      .map(|value| value.trim())
+      .filter(|value| !value.is_empty())
+  }
+}
+
+/// The name of the deploy form field for an item of a keyed resource, e.g. `config_store.<store>.<key>`
+fn keyed_field(prefix: &str, name: &str, item: &DictionaryItemSpec) -> String {
+  format!("{}.{}.{}", prefix, name, item.key)
+}
+
+/// A problem with a single value entered on the deploy form.
+#[derive(Debug)]
+pub struct FieldError {
+  pub field: String,
+  pub message: String,
+}
+
+impl FieldError {
+  pub fn new(field: String, message: &str) -> FieldError {
+    FieldError {
+      field,
+      message: message.to_string(),
+    }
+  }
+}
+
+/// Every problem found while validating the deploy form.
+#[derive(Debug)]
+pub struct ValidationError {
+  pub errors: Vec<FieldError>,
+}
+
+impl ValidationError {
+  /// The message for a field, if its value was rejected
+  pub fn get(&self, field: &str) -> Option<&str> {
+    self
+      .errors
+      .iter()
+      .find(|error| error.field == field)
+      .map(|error| error.message.as_str())
+  }
+}
+
+impl fmt::Display for ValidationError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let messages: Vec<&str> = self.errors.iter().map(|error| error.message.as_str()).collect();
+    write!(f, "{}", messages.join("; "))
+  }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Checks that a value is a bare hostname or IP address, without a scheme, port or path.
+pub fn is_valid_host(host: &str) -> bool {
+  if host.parse::<IpAddr>().is_ok() {
+    return true;
+  }
+
+  host.len() <= 253
+    && host.split('.').all(|label| {
+      !label.is_empty()
+        && label.len() <= 63
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct DeployConfigSpec {
+  #[serde(default, deserialize_with = "named_tables")]
   pub backends: Vec<BackendSpec>,
+  #[serde(default, deserialize_with = "named_tables")]
   pub dictionaries: Vec<DictionarySpec>,
+  #[serde(default)]
+  pub config_stores: BTreeMap<String, ConfigStoreSpec>,
+  #[serde(default)]
+  pub kv_stores: BTreeMap<String, KvStoreSpec>,
+  #[serde(default)]
+  pub secret_stores: BTreeMap<String, SecretStoreSpec>,
+  #[serde(default)]
+  pub log_endpoints: BTreeMap<String, LogEndpointSpec>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 pub struct BackendSpec {
   pub prompt: Option<String>,
+  pub description: Option<String>,
+  #[serde(default)]
   pub name: String,
   pub address: String,
   pub port: Option<i32>,
+  /// Defaults to true when the port is 443
+  pub use_ssl: Option<bool>,
+  pub ssl_cert_hostname: Option<String>,
+  pub ssl_sni_hostname: Option<String>,
+  pub override_host: Option<String>,
+  /// Timeouts in milliseconds
+  pub connect_timeout: Option<i32>,
+  pub first_byte_timeout: Option<i32>,
+  pub between_bytes_timeout: Option<i32>,
+  /// Created alongside the backend and named after it
+  pub healthcheck: Option<HealthcheckSpec>,
+  /// Code of the POP to use as a shield, e.g. "iad-va-us"
+  pub shield: Option<String>,
+}
+
+/// A health check polling a backend, declared as `[setup.backends.<name>.healthcheck]`.
+#[derive(Serialize, Deserialize)]
+pub struct HealthcheckSpec {
+  pub path: String,
+  /// Defaults to the backend's override host, then its address
+  pub host: Option<String>,
+  /// Defaults to HEAD
+  pub method: Option<String>,
+  /// The status code of a healthy response, 200 by default
+  pub expected_response: Option<i32>,
+  /// Milliseconds between checks
+  pub check_interval: Option<i32>,
+  /// How many of the most recent checks must pass for the backend to be healthy
+  pub threshold: Option<i32>,
+}
+
+impl BackendSpec {
+  pub fn address_field(&self) -> String {
+    format!("backend.{}.address", self.name)
+  }
+
+  pub fn port_field(&self) -> String {
+    format!("backend.{}.port", self.name)
+  }
+
+  /// The optional host names, which may refer to variables like the address
+  fn hostnames(&self) -> Vec<&String> {
+    let mut hostnames = vec![
+      self.override_host.as_ref(),
+      self.ssl_cert_hostname.as_ref(),
+      self.ssl_sni_hostname.as_ref(),
+    ];
+    if let Some(healthcheck) = self.healthcheck.as_ref() {
+      hostnames.push(healthcheck.host.as_ref());
+    }
+    hostnames.into_iter().flatten().collect()
+  }
+
+  fn hostnames_mut(&mut self) -> Vec<&mut String> {
+    let mut hostnames = vec![
+      self.override_host.as_mut(),
+      self.ssl_cert_hostname.as_mut(),
+      self.ssl_sni_hostname.as_mut(),
+    ];
+    if let Some(healthcheck) = self.healthcheck.as_mut() {
+      hostnames.push(healthcheck.host.as_mut());
+    }
+    hostnames.into_iter().flatten().collect()
+  }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct DictionarySpec {
+  #[serde(default)]
   pub name: String,
+  #[serde(default, deserialize_with = "named_tables")]
   pub items: Vec<DictionaryItemSpec>,
+  /// Name of a secret store to hold this dictionary's `password` items instead of the dictionary
+  pub secret_store: Option<String>,
+}
+
+impl Named for BackendSpec {
+  fn set_name(&mut self, name: String) {
+    self.name = name;
+  }
+}
+
+impl Named for DictionarySpec {
+  fn set_name(&mut self, name: String) {
+    self.name = name;
+  }
+}
+
+impl Named for DictionaryItemSpec {
+  fn set_name(&mut self, key: String) {
+    self.key = key;
+  }
+}
+
+impl Named for KvItemSpec {
+  fn set_name(&mut self, key: String) {
+    self.key = key;
+  }
+}
+
+impl DictionarySpec {
+  /// Whether an item is written to the secret store rather than the dictionary
+  pub fn is_secret(&self, item: &DictionaryItemSpec) -> bool {
+    self.secret_store.is_some() && item.input_type == InputType::Password
+  }
+
+  /// The name of the deploy form field for one of this dictionary's items
+  pub fn field_name(&self, item: &DictionaryItemSpec) -> String {
+    format!("dict.{}.{}", self.name, item.key)
+  }
+}
+
+/// A config store, declared as `[setup.config_stores.<name>]` with `items.<key>` tables.
+#[derive(Serialize, Deserialize)]
+pub struct ConfigStoreSpec {
+  pub description: Option<String>,
+  #[serde(default)]
+  pub items: BTreeMap<String, DictionaryItemSpec>,
+}
+
+/// A KV store, declared as `[setup.kv_stores.<name>]` with `[[setup.kv_stores.<name>.items]]`
+/// tables like the fastly CLI, or with `items.<key>` tables.
+#[derive(Serialize, Deserialize)]
+pub struct KvStoreSpec {
+  pub description: Option<String>,
+  #[serde(default, deserialize_with = "named_tables")]
+  pub items: Vec<KvItemSpec>,
+}
+
+/// A KV store entry, seeded either from a literal value or from a file in the repository.
+#[derive(Serialize, Deserialize)]
+pub struct KvItemSpec {
+  /// Taken from the table name when the item is declared as a keyed table
+  #[serde(default)]
+  pub key: String,
+  pub value: Option<String>,
+  /// Path of the file, relative to the directory containing fastly.toml
+  pub file: Option<String>,
+}
+
+/// A secret store, declared as `[setup.secret_stores.<name>]` with
+/// `[[setup.secret_stores.<name>.entries]]` tables like the fastly CLI, or with `entries.<key>`
+/// tables. Entries are always entered as passwords.
+#[derive(Serialize, Deserialize)]
+pub struct SecretStoreSpec {
+  pub description: Option<String>,
+  #[serde(default, deserialize_with = "named_tables")]
+  pub entries: Vec<DictionaryItemSpec>,
+}
+
+/// A logging endpoint, declared as `[setup.log_endpoints.<name>]`. Each `fields.<key>` table
+/// sets one of the provider's API settings, either to a fixed value or to one entered by the user.
+#[derive(Serialize, Deserialize)]
+pub struct LogEndpointSpec {
+  pub provider: LogProvider,
+  pub description: Option<String>,
+  #[serde(default)]
+  pub fields: BTreeMap<String, DictionaryItemSpec>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogProvider {
+  Https,
+  Syslog,
+  /// Amazon S3 or any S3-compatible service, selected with the `domain` field
+  S3,
+}
+
+impl LogProvider {
+  /// The path of the provider's logging API, e.g. `/service/<id>/version/<v>/logging/https`
+  pub fn path(&self) -> &'static str {
+    match self {
+      LogProvider::Https => "https",
+      LogProvider::Syslog => "syslog",
+      LogProvider::S3 => "s3",
+    }
+  }
+
+  pub fn required_fields(&self) -> &'static [&'static str] {
+    match self {
+      LogProvider::Https => &["url"],
+      LogProvider::Syslog => &["address"],
+      LogProvider::S3 => &["bucket_name"],
+    }
+  }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct DictionaryItemSpec {
+  /// Taken from the table name when the item is declared as a keyed table
+  #[serde(default)]
   pub key: String,
-  pub input_type: String,
+  #[serde(default)]
+  pub input_type: InputType,
   pub prompt: Option<String>,
-  pub value: Option<String>
+  pub description: Option<String>,
+  pub value: Option<String>,
+  /// Allowed values for `select` inputs
+  #[serde(default)]
+  pub options: Vec<String>,
+  /// A regular expression the whole value must match
+  pub pattern: Option<String>,
+  pub min_length: Option<usize>,
+  pub max_length: Option<usize>,
+  /// Number of characters in a `generated` value
+  pub length: Option<usize>,
+  /// Characters a `generated` value is drawn from: "alphanumeric" (the default), "hex",
+  /// "base64url", or a string listing the characters themselves
+  pub alphabet: Option<String>,
+  /// Also store a `generated` value as a GitHub Actions secret with this name
+  pub github_secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum InputType {
+  #[default]
+  String,
+  Password,
+  Url,
+  Hostname,
+  Integer,
+  Boolean,
+  Email,
+  Select,
+  /// A random value produced at deploy time, which the user never sees
+  Generated,
+}
+
+impl DictionaryItemSpec {
+  /// The name shown to the user, falling back to the item key.
+  pub fn label(&self) -> &str {
+    self.prompt.as_deref().unwrap_or(&self.key)
+  }
+
+  fn alphabet(&self) -> Vec<char> {
+    match self.alphabet.as_deref() {
+      None | Some("alphanumeric") => ('a'..='z').chain('A'..='Z').chain('0'..='9').collect(),
+      Some("hex") => ('0'..='9').chain('a'..='f').collect(),
+      Some("base64url") => ('a'..='z')
+        .chain('A'..='Z')
+        .chain('0'..='9')
+        .chain(vec!['-', '_'])
+        .collect(),
+      Some(characters) => {
+        let mut alphabet: Vec<char> = characters.chars().collect();
+        alphabet.sort_unstable();
+        alphabet.dedup();
+        alphabet
+      }
+    }
+  }
+
+  /// Produces a random value for a `generated` item.
+  pub fn generate(&self) -> String {
+    random_string(self.length.unwrap_or(DEFAULT_GENERATED_LENGTH), &self.alphabet())
+  }
+
+  /// Checks a value against the item's input type and constraints.
+  pub fn validate(&self, value: &str) -> Result<()> {
+    let label = self.label();
+
+    let is_valid_type = match self.input_type {
+      InputType::String | InputType::Password | InputType::Generated => true,
+      InputType::Url => is_valid_url(value),
+      InputType::Hostname => is_valid_host(value),
+      InputType::Integer => value.parse::<i64>().is_ok(),
+      InputType::Boolean => value == "true" || value == "false",
+      InputType::Email => is_valid_email(value),
+      InputType::Select => self.options.iter().any(|option| option == value),
+    };
+
+    if !is_valid_type {
+      match self.input_type {
+        InputType::Url => bail!("{} must be an http:// or https:// URL", label),
+        InputType::Hostname => bail!("{} must be a hostname or IP address", label),
+        InputType::Integer => bail!("{} must be a whole number", label),
+        InputType::Boolean => bail!("{} must be true or false", label),
+        InputType::Email => bail!("{} must be an email address", label),
+        _ => bail!("{} must be one of: {}", label, self.options.join(", ")),
+      }
+    }
+
+    let length = value.chars().count();
+    if let Some(min_length) = self.min_length {
+      if length < min_length {
+        bail!("{} must be at least {} characters long", label, min_length);
+      }
+    }
+    if let Some(max_length) = self.max_length {
+      if length > max_length {
+        bail!("{} must be at most {} characters long", label, max_length);
+      }
+    }
+
+    if let Some(pattern) = &self.pattern {
+      // Like the HTML pattern attribute, the whole value must match
+      let regex = match Regex::new(&format!("^(?:{})$", pattern)) {
+        Ok(regex) => regex,
+        Err(err) => bail!("The pattern for {} is not a valid regular expression: {}", label, err),
+      };
+      if !regex.is_match(value) {
+        bail!("{} is not in the expected format", label);
+      }
+    }
+
+    Ok(())
+  }
+}
+
+/// Checks that a value is an absolute http:// or https:// URL with a valid host.
+pub fn is_valid_url(url: &str) -> bool {
+  let rest = match url.strip_prefix("https://").or_else(|| url.strip_prefix("http://")) {
+    Some(rest) => rest,
+    None => return false,
+  };
+
+  let authority = rest.split(&['/', '?', '#'][..]).next().unwrap_or("");
+  let host = match authority.rsplit_once(':') {
+    Some((host, port)) if port.parse::<u16>().is_ok() => host,
+    _ => authority,
+  };
+
+  is_valid_host(host.trim_start_matches('[').trim_end_matches(']'))
+}
+
+/// Checks that a value looks like an email address with a routable domain.
+pub fn is_valid_email(email: &str) -> bool {
+  match email.rsplit_once('@') {
+    Some((local, domain)) => {
+      !local.is_empty()
+        && !local.chars().any(|c| c.is_whitespace() || c == '@')
+        && domain.contains('.')
+        && is_valid_host(domain)
+    }
+    None => false,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn deploy_config(manifest: &str, params: &[(&str, &str)]) -> DeployConfig {
+    DeployConfig {
+      spec: DeployConfigSpec::from_toml(manifest).unwrap(),
+      params: params
+        .iter()
+        .map(|(field, value)| (field.to_string(), value.to_string()))
+        .collect(),
+      variables: Variables::default(),
+      generated: BTreeMap::new(),
+    }
+  }
+
+  fn item(input_type: &str, extra: &str) -> DictionaryItemSpec {
+    toml::from_str(&format!("key = \"item\"\ninput_type = \"{}\"\n{}", input_type, extra)).unwrap()
+  }
+
+  #[test]
+  fn validates_input_types() {
+    assert!(item("string", "").validate("anything at all").is_ok());
+    assert!(item("url", "").validate("https://example.com:8443/path?query").is_ok());
+    assert!(item("url", "").validate("ftp://example.com").is_err());
+    assert!(item("url", "").validate("https://exa mple.com").is_err());
+    assert!(item("hostname", "").validate("origin.example.com").is_ok());
+    assert!(item("hostname", "").validate("192.0.2.1").is_ok());
+    assert!(item("hostname", "").validate("https://example.com").is_err());
+    assert!(item("integer", "").validate("-42").is_ok());
+    assert!(item("integer", "").validate("4.2").is_err());
+    assert!(item("boolean", "").validate("true").is_ok());
+    assert!(item("boolean", "").validate("yes").is_err());
+    assert!(item("email", "").validate("user@example.com").is_ok());
+    assert!(item("email", "").validate("user@localhost").is_err());
+
+    let select = item("select", r#"options = ["red", "green"]"#);
+    assert!(select.validate("green").is_ok());
+    assert!(select.validate("blue").is_err());
+  }
+
+  #[test]
+  fn validates_lengths_and_patterns() {
+    let constrained = item("string", "min_length = 2\nmax_length = 4\npattern = \"[a-z]+\"");
+    assert!(constrained.validate("ab").is_ok());
+    assert!(constrained.validate("abcd").is_ok());
+    assert!(constrained.validate("a").is_err());
+    assert!(constrained.validate("abcde").is_err());
+    // The pattern must match the whole value
+    assert!(constrained.validate("ab1").is_err());
+
+    let invalid = item("string", "pattern = \"[\"");
+    assert!(invalid.validate("a").is_err());
+  }
+
+  #[test]
+  fn finds_references() {
+    let template = "${service_id}.example.com/${ domain }";
+    let found = references(template).unwrap();
+    assert_eq!(found.len(), 2);
+    assert_eq!(found[0], (0..13, "service_id"));
+    assert_eq!(found[1], (26..37, "domain"));
+
+    assert!(references("no variables").unwrap().is_empty());
+    assert!(references("${service_id").is_err());
+  }
+
+  #[test]
+  fn checks_references() {
+    assert!(check_references("${github.login}-${github.repository}").is_ok());
+    assert!(check_references("${unknown}").is_err());
+    assert!(check_references("${domain").is_err());
+  }
+
+  #[test]
+  fn interpolates_known_variables() {
+    let mut variables = Variables::default();
+    variables.set("service_id", "abc123");
+
+    assert!(variables.is_ready("https://${service_id}.example.com"));
+    assert!(!variables.is_ready("https://${domain}"));
+    assert_eq!(
+      variables.interpolate("https://${service_id}.example.com/${ service_id }").unwrap(),
+      "https://abc123.example.com/abc123"
+    );
+    assert_eq!(variables.interpolate("plain").unwrap(), "plain");
+    assert!(variables.interpolate("${domain}").is_err());
+  }
+
+  #[test]
+  fn interpolates_defaults_but_not_entered_values() {
+    let manifest = r#"
+      [[setup.dictionaries]]
+      name = "settings"
+      [[setup.dictionaries.items]]
+      key = "origin"
+      value = "https://${github.login}.github.io"
+      [[setup.dictionaries.items]]
+      key = "password"
+      input_type = "password"
+      "#;
+
+    let mut deploy = deploy_config(manifest, &[("dict.settings.password", "pa${ss")]);
+    // Defaults are not checked until the variables they use are known
+    assert!(deploy.validate().is_ok());
+
+    deploy.variables.set("github.login", "octocat");
+    assert!(deploy.validate().is_ok());
+    let dict = &deploy.spec.dictionaries[0];
+    assert_eq!(
+      deploy.dictionary_item_value(dict, &dict.items[0]).unwrap(),
+      "https://octocat.github.io"
+    );
+    assert_eq!(deploy.dictionary_item_value(dict, &dict.items[1]).unwrap(), "pa${ss");
+  }
+
+  #[test]
+  fn resolves_defaults_submitted_from_the_rendered_form() {
+    let manifest = r#"
+      [setup.backends.origin]
+      prompt = "Origin"
+      address = "${github.login}.github.io"
+
+      [[setup.dictionaries]]
+      name = "settings"
+      [[setup.dictionaries.items]]
+      key = "site"
+      input_type = "url"
+      value = "https://${github.login}.github.io"
+      [[setup.dictionaries.items]]
+      key = "greeting"
+      value = "Hello"
+      "#;
+
+    // Browsers submit every text input, with an empty string when it has no value
+    let spec = DeployConfigSpec::from_toml(manifest).unwrap();
+    let params: Vec<(String, String)> = crate::form::fields(&spec, None)
+      .into_iter()
+      .map(|field| (field.name, field.value.unwrap_or_default()))
+      .collect();
+    let params: Vec<(&str, &str)> = params.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+
+    let mut deploy = deploy_config(manifest, &params);
+    deploy.variables.set("github.login", "octocat");
+    deploy.validate().unwrap();
+    deploy.resolve_backends().unwrap();
+
+    assert_eq!(deploy.spec.backends[0].address, "octocat.github.io");
+    let dict = &deploy.spec.dictionaries[0];
+    assert_eq!(
+      deploy.dictionary_item_value(dict, &dict.items[0]).unwrap(),
+      "https://octocat.github.io"
+    );
+    assert_eq!(deploy.dictionary_item_value(dict, &dict.items[1]).unwrap(), "Hello");
+  }
+
+  #[test]
+  fn rejects_unknown_variables_in_defaults() {
+    assert!(DeployConfigSpec::from_toml(
+      r#"
+      [[setup.dictionaries]]
+      name = "settings"
+      [[setup.dictionaries.items]]
+      key = "origin"
+      value = "${origin}"
+      "#,
+    )
+    .is_err());
+  }
+
+  #[test]
+  fn resolves_template_backend_addresses_only() {
+    let manifest = r#"
+      [setup.backends.origin]
+      address = "${github.login}.github.io"
+      [setup.backends.api]
+      prompt = "API host"
+      address = "api.example.com"
+      "#;
+
+    let mut deploy = deploy_config(manifest, &[("backend.api.address", "${github.login}.example.com")]);
+    let err = deploy.validate().unwrap_err();
+    assert!(err.get("backend.api.address").is_some());
+
+    let mut deploy = deploy_config(manifest, &[("backend.api.address", "api.example.net")]);
+    deploy.validate().unwrap();
+    deploy.variables.set("github.login", "octocat");
+    deploy.resolve_backends().unwrap();
+    let address = |name: &str| {
+      let backend = deploy.spec.backends.iter().find(|backend| backend.name == name).unwrap();
+      backend.address.to_owned()
+    };
+    assert_eq!(address("origin"), "octocat.github.io");
+    assert_eq!(address("api"), "api.example.net");
+  }
+
+  #[test]
+  fn checks_manifest_version() {
+    assert!(DeployConfigSpec::from_toml("").is_ok());
+    assert!(DeployConfigSpec::from_toml("manifest_version = 3").is_ok());
+    assert!(DeployConfigSpec::from_toml("manifest_version = \"2\"").is_ok());
+    assert!(DeployConfigSpec::from_toml("manifest_version = 4").is_err());
+    assert!(DeployConfigSpec::from_toml("manifest_version = 0").is_err());
+    assert!(DeployConfigSpec::from_toml("manifest_version = \"latest\"").is_err());
+  }
+
+  fn assert_rejected(manifest: &str, message: &str) {
+    match DeployConfigSpec::from_toml(manifest) {
+      Ok(_) => panic!("expected {:?} to be rejected", message),
+      Err(err) => assert_eq!(err.to_string(), message),
+    }
+  }
+
+  #[test]
+  fn rejects_resources_without_names() {
+    assert_rejected(
+      r#"
+      [[setup.backends]]
+      address = "example.com"
+      "#,
+      "Every backend must have a name",
+    );
+    assert_rejected(
+      r#"
+      [[setup.dictionaries]]
+      [[setup.dictionaries.items]]
+      key = "greeting"
+      value = "hello"
+      "#,
+      "Every dictionary must have a name",
+    );
+    assert_rejected(
+      r#"
+      [[setup.dictionaries]]
+      name = "settings"
+      [[setup.dictionaries.items]]
+      value = "hello"
+      "#,
+      "Every item of dictionary settings must have a key",
+    );
+  }
+
+  #[test]
+  fn rejects_duplicate_names() {
+    assert_rejected(
+      r#"
+      [[setup.backends]]
+      name = "origin"
+      address = "example.com"
+      [[setup.backends]]
+      name = "origin"
+      address = "example.net"
+      "#,
+      "More than one backend has the name origin",
+    );
+    assert_rejected(
+      r#"
+      [[setup.dictionaries]]
+      name = "settings"
+      [[setup.dictionaries.items]]
+      key = "greeting"
+      value = "hello"
+      [[setup.dictionaries.items]]
+      key = "greeting"
+      value = "hi"
+      "#,
+      "More than one item of dictionary settings has the key greeting",
+    );
+  }
+
+  #[test]
+  fn reads_store_items_as_arrays_of_tables() {
+    let spec = DeployConfigSpec::from_toml(
+      r#"
+      manifest_version = 3
+
+      [setup.kv_stores.assets]
+      [[setup.kv_stores.assets.items]]
+      key = "greeting"
+      value = "hello"
+      [[setup.kv_stores.assets.items]]
+      key = "page"
+      file = "static/page.html"
+
+      [setup.secret_stores.credentials]
+      [[setup.secret_stores.credentials.entries]]
+      key = "api_key"
+      description = "Key for the origin API"
+      "#,
+    )
+    .unwrap();
+
+    let items = &spec.kv_stores["assets"].items;
+    assert_eq!(items.len(), 2);
+    assert_eq!(items[0].key, "greeting");
+    assert_eq!(items[0].value.as_deref(), Some("hello"));
+    assert_eq!(items[1].key, "page");
+    assert_eq!(items[1].file.as_deref(), Some("static/page.html"));
+
+    let entries = &spec.secret_stores["credentials"].entries;
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].key, "api_key");
+    assert!(entries[0].input_type == InputType::Password);
+  }
+
+  #[test]
+  fn reads_store_items_as_keyed_tables() {
+    let spec = DeployConfigSpec::from_toml(
+      r#"
+      manifest_version = 3
+
+      [setup.kv_stores.assets.items.greeting]
+      value = "hello"
+
+      [setup.secret_stores.credentials.entries.api_key]
+      description = "Key for the origin API"
+      "#,
+    )
+    .unwrap();
+
+    assert_eq!(spec.kv_stores["assets"].items[0].key, "greeting");
+    assert_eq!(spec.secret_stores["credentials"].entries[0].key, "api_key");
+  }
+
+  #[test]
+  fn rejects_store_items_without_keys() {
+    assert!(DeployConfigSpec::from_toml(
+      r#"
+      [[setup.kv_stores.assets.items]]
+      value = "hello"
+      "#,
+    )
+    .is_err());
+    assert!(DeployConfigSpec::from_toml(
+      r#"
+      [[setup.secret_stores.credentials.entries]]
+      description = "Key for the origin API"
+      "#,
+    )
+    .is_err());
+  }
 }
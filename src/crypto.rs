@@ -0,0 +1,229 @@
+use anyhow::{bail, Result};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use fastly::ConfigStore;
+use rand::rngs::OsRng;
+use rand::{Rng, RngCore};
+
+const NONCE_LENGTH: usize = 24;
+const KEY_LENGTH: usize = 32;
+const TOKEN_LENGTH: usize = 32;
+
+/// Generates a random, URL-safe token suitable for nonces and session identifiers.
+pub fn random_token() -> String {
+  let mut token = [0u8; TOKEN_LENGTH];
+  OsRng.fill_bytes(&mut token);
+  base64::encode_config(token, base64::URL_SAFE_NO_PAD)
+}
+
+/// Generates a random string of characters drawn uniformly from an alphabet.
+pub fn random_string(length: usize, alphabet: &[char]) -> String {
+  (0..length)
+    .map(|_| alphabet[OsRng.gen_range(0, alphabet.len())])
+    .collect()
+}
+
+/// Compares two secrets without leaking the position of the first difference through timing.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+  if a.len() != b.len() {
+    return false;
+  }
+  a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// A set of keys used to seal cookies with authenticated encryption.
+///
+/// Keys are read from an edge dictionary containing:
+///
+/// * `active_key_id` - the ID of the key used to seal new cookies
+/// * `accepted_key_ids` - optional comma-separated IDs of older keys that can still open cookies
+/// * `key_<id>` - 32 random bytes, base64-encoded, for every ID above
+///
+/// To rotate keys, add a new `key_<id>` entry, move the old ID into `accepted_key_ids` and
+/// set `active_key_id` to the new ID.
+pub struct Keyring {
+  active_id: String,
+  keys: Vec<(String, XChaCha20Poly1305)>,
+}
+
+impl Keyring {
+  pub fn get_default() -> Result<Keyring> {
+    Keyring::from_dictionary("cookie_keys")
+  }
+
+  pub fn from_dictionary(dictionary_name: &str) -> Result<Keyring> {
+    let dictionary = ConfigStore::open(dictionary_name);
+
+    let active_id = match dictionary.get("active_key_id") {
+      Some(id) => id.trim().to_string(),
+      None => bail!("No active_key_id set in the {} dictionary", dictionary_name),
+    };
+
+    let mut ids = vec![active_id.to_owned()];
+    if let Some(accepted) = dictionary.get("accepted_key_ids") {
+      for id in accepted.split(',').map(str::trim) {
+        if !id.is_empty() && !ids.iter().any(|existing| existing == id) {
+          ids.push(id.to_string());
+        }
+      }
+    }
+
+    let mut keys = vec![];
+    for id in ids {
+      let key = match dictionary.get(&format!("key_{}", id)) {
+        Some(key) => base64::decode(key.trim())?,
+        None => bail!("Key {} is missing from the {} dictionary", id, dictionary_name),
+      };
+      keys.push((id, key));
+    }
+
+    Keyring::from_keys(&active_id, keys)
+  }
+
+  /// Builds a keyring from raw keys, the first of which need not be the active one.
+  pub fn from_keys(active_id: &str, keys: Vec<(String, Vec<u8>)>) -> Result<Keyring> {
+    let mut ciphers = vec![];
+    for (id, key) in keys {
+      if key.len() != KEY_LENGTH {
+        bail!("Key {} must be exactly {} bytes long", id, KEY_LENGTH);
+      }
+      ciphers.push((id, XChaCha20Poly1305::new(Key::from_slice(&key))));
+    }
+
+    if !ciphers.iter().any(|(id, _)| id == active_id) {
+      bail!("Active key {} is not loaded", active_id);
+    }
+
+    Ok(Keyring {
+      active_id: active_id.to_string(),
+      keys: ciphers,
+    })
+  }
+
+  /// Encrypts a value with the active key, binding it to the name of the cookie it will be stored in.
+  pub fn seal(&self, name: &str, value: &[u8]) -> Result<String> {
+    let mut nonce = [0u8; NONCE_LENGTH];
+    OsRng.fill_bytes(&mut nonce);
+
+    let cipher = match self.keys.iter().find(|(id, _)| id == &self.active_id) {
+      Some((_, cipher)) => cipher,
+      None => bail!("Active key {} is not loaded", self.active_id),
+    };
+
+    let ciphertext = match cipher.encrypt(
+      XNonce::from_slice(&nonce),
+      Payload {
+        msg: value,
+        aad: name.as_bytes(),
+      },
+    ) {
+      Ok(ciphertext) => ciphertext,
+      Err(_) => bail!("Unable to seal {} cookie", name),
+    };
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+
+    Ok(format!(
+      "{}.{}",
+      self.active_id,
+      base64::encode_config(sealed, base64::URL_SAFE_NO_PAD)
+    ))
+  }
+
+  /// Decrypts a sealed cookie value. Returns `None` if the value has been tampered with,
+  /// belongs to another cookie, or was sealed with a key that is no longer accepted.
+  pub fn open(&self, name: &str, sealed: &str) -> Option<Vec<u8>> {
+    let mut parts = sealed.splitn(2, '.');
+    let key_id = parts.next()?;
+    let payload = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+
+    if payload.len() < NONCE_LENGTH {
+      return None;
+    }
+
+    let (_, cipher) = self.keys.iter().find(|(id, _)| id == key_id)?;
+    let (nonce, ciphertext) = payload.split_at(NONCE_LENGTH);
+
+    cipher
+      .decrypt(
+        XNonce::from_slice(nonce),
+        Payload {
+          msg: ciphertext,
+          aad: name.as_bytes(),
+        },
+      )
+      .ok()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn keyring(active_id: &str, ids: &[&str]) -> Keyring {
+    let keys = ids
+      .iter()
+      .map(|id| (id.to_string(), vec![id.as_bytes()[0]; KEY_LENGTH]))
+      .collect();
+    Keyring::from_keys(active_id, keys).unwrap()
+  }
+
+  #[test]
+  fn opens_sealed_values() {
+    let keys = keyring("1", &["1"]);
+    let sealed = keys.seal("cookie", b"value").unwrap();
+
+    assert!(sealed.starts_with("1."));
+    assert_eq!(keys.open("cookie", &sealed), Some(b"value".to_vec()));
+  }
+
+  #[test]
+  fn rejects_tampered_values() {
+    let keys = keyring("1", &["1"]);
+    let sealed = keys.seal("cookie", b"value").unwrap();
+
+    let mut tampered = sealed.into_bytes();
+    let last = tampered.len() - 1;
+    tampered[last] = if tampered[last] == b'A' { b'B' } else { b'A' };
+    let tampered = String::from_utf8(tampered).unwrap();
+
+    assert_eq!(keys.open("cookie", &tampered), None);
+    assert_eq!(keys.open("cookie", "1.not-base64!"), None);
+    assert_eq!(keys.open("cookie", "garbage"), None);
+  }
+
+  #[test]
+  fn binds_values_to_their_name() {
+    let keys = keyring("1", &["1"]);
+    let sealed = keys.seal("cookie", b"value").unwrap();
+
+    assert_eq!(keys.open("other", &sealed), None);
+  }
+
+  #[test]
+  fn opens_values_sealed_with_accepted_keys() {
+    let old = keyring("1", &["1"]);
+    let sealed = old.seal("cookie", b"value").unwrap();
+
+    let rotated = keyring("2", &["2", "1"]);
+    assert_eq!(rotated.open("cookie", &sealed), Some(b"value".to_vec()));
+    assert!(rotated.seal("cookie", b"value").unwrap().starts_with("2."));
+
+    let retired = keyring("2", &["2"]);
+    assert_eq!(retired.open("cookie", &sealed), None);
+  }
+
+  #[test]
+  fn rejects_invalid_keys() {
+    assert!(Keyring::from_keys("1", vec![("1".to_string(), vec![0; 16])]).is_err());
+    assert!(Keyring::from_keys("2", vec![("1".to_string(), vec![0; KEY_LENGTH])]).is_err());
+  }
+
+  #[test]
+  fn compares_in_constant_time() {
+    assert!(constant_time_eq("token", "token"));
+    assert!(!constant_time_eq("token", "tokem"));
+    assert!(!constant_time_eq("token", "tokens"));
+  }
+}
@@ -0,0 +1,61 @@
+use crate::crypto::constant_time_eq;
+use crate::ActionParams;
+use anyhow::{bail, Result};
+use fastly::{http::header, Request};
+
+/// Name of the hidden form field carrying the synchronizer token
+pub const TOKEN_FIELD: &str = "csrf_token";
+
+/// Verifies that a state-changing request was submitted from one of our own pages.
+///
+/// The request must come from the same origin, and its form body must carry the session's
+/// synchronizer token. The body is restored afterwards so that handlers can parse it as usual.
+pub fn verify_request(req: &mut Request, expected_token: &str) -> Result<()> {
+  verify_origin(req)?;
+
+  let form: ActionParams = req.take_body_form()?;
+
+  let is_valid = match form.get(TOKEN_FIELD) {
+    Some(token) => constant_time_eq(token, expected_token),
+    None => false,
+  };
+
+  if !is_valid {
+    bail!("missing or invalid {} form field", TOKEN_FIELD);
+  }
+
+  req.set_body_form(&form)?;
+  Ok(())
+}
+
+/// Checks the `Origin` header, falling back to `Referer` for clients that do not send it.
+fn verify_origin(req: &Request) -> Result<()> {
+  let host = match get_header(req, header::HOST) {
+    Some(host) => host,
+    None => bail!("request has no Host header"),
+  };
+  let expected_origin = format!("https://{}", host);
+
+  if let Some(origin) = get_header(req, header::ORIGIN) {
+    if origin != expected_origin {
+      bail!("cross-origin request from {}", origin);
+    }
+    return Ok(());
+  }
+
+  if let Some(referer) = get_header(req, header::REFERER) {
+    if referer != expected_origin && !referer.starts_with(&format!("{}/", expected_origin)) {
+      bail!("cross-origin request from {}", referer);
+    }
+    return Ok(());
+  }
+
+  bail!("request has neither an Origin nor a Referer header")
+}
+
+fn get_header(req: &Request, name: header::HeaderName) -> Option<&str> {
+  match req.get_header(name) {
+    Some(value) => value.to_str().ok(),
+    None => None,
+  }
+}
@@ -0,0 +1,277 @@
+use crate::config::{uses_variables, BackendSpec, DeployConfigSpec, DictionaryItemSpec, InputType, ValidationError};
+use crate::github::GitHubUser;
+use crate::scdn::FastlyServiceSummary;
+use crate::ActionParams;
+use serde::Serialize;
+
+/// An input on the deploy form, with everything the template needs to render the right control.
+#[derive(Serialize)]
+pub struct FormField {
+  /// The submitted param, e.g. `dict.<dictionary>.<key>` or `config_store.<store>.<key>`
+  pub name: String,
+  pub label: String,
+  pub description: Option<String>,
+  /// The `type` attribute of plain inputs
+  pub html_type: &'static str,
+  pub is_select: bool,
+  pub is_checkbox: bool,
+  pub options: Vec<FormOption>,
+  pub value: Option<String>,
+  /// A default that refers to variables, which is resolved at deploy time when the field is left blank
+  pub placeholder: Option<String>,
+  pub checked: bool,
+  pub required: bool,
+  pub pattern: Option<String>,
+  pub min_length: Option<usize>,
+  pub max_length: Option<usize>,
+  /// Why the submitted value was rejected
+  pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct FormOption {
+  pub value: String,
+  pub selected: bool,
+}
+
+impl FormField {
+  pub fn from_item(name: String, item: &DictionaryItemSpec) -> FormField {
+    // Secrets are never echoed back into the page
+    let value = match item.input_type {
+      InputType::Password => None,
+      _ => item.value.to_owned(),
+    };
+
+    let mut field = FormField::text(name, item.label(), value, item.value.is_none());
+    field.hint_template();
+    field.description = item.description.to_owned();
+    field.html_type = match item.input_type {
+      InputType::Password => "password",
+      InputType::Url => "url",
+      InputType::Email => "email",
+      InputType::Integer => "number",
+      _ => "text",
+    };
+    field.is_select = item.input_type == InputType::Select;
+    field.is_checkbox = item.input_type == InputType::Boolean;
+    field.options = item
+      .options
+      .iter()
+      .map(|option| FormOption {
+        value: option.to_owned(),
+        selected: false,
+      })
+      .collect();
+    field.pattern = item.pattern.to_owned();
+    field.min_length = item.min_length;
+    field.max_length = item.max_length;
+    field.select_value();
+    field
+  }
+
+  /// Builds the address and port inputs for a prompted backend.
+  pub fn from_backend(backend: &BackendSpec, prompt: &str) -> Vec<FormField> {
+    let mut address = FormField::text(
+      backend.address_field(),
+      &format!("{} address", prompt),
+      Some(backend.address.to_owned()),
+      true,
+    );
+    address.hint_template();
+
+    let mut port = FormField::text(
+      backend.port_field(),
+      &format!("{} port", prompt),
+      backend.port.map(|port| port.to_string()),
+      false,
+    );
+    port.html_type = "number";
+
+    vec![address, port]
+  }
+
+  fn text(name: String, label: &str, value: Option<String>, required: bool) -> FormField {
+    FormField {
+      name,
+      label: label.to_string(),
+      description: None,
+      html_type: "text",
+      is_select: false,
+      is_checkbox: false,
+      options: vec![],
+      value,
+      placeholder: None,
+      checked: false,
+      required,
+      pattern: None,
+      min_length: None,
+      max_length: None,
+      error: None,
+    }
+  }
+
+  /// Shows a default that refers to variables as a placeholder rather than a value. Submitted
+  /// values are used as they are, so pre-filling it would send the unresolved `${name}` text.
+  fn hint_template(&mut self) {
+    if self.value.as_deref().is_some_and(uses_variables) {
+      self.placeholder = self.value.take();
+      self.required = false;
+    }
+  }
+
+  /// Restores the submitted value, unless it is secret, and attaches the field's validation error.
+  fn restore(&mut self, params: &ActionParams, errors: &ValidationError) {
+    if self.html_type != "password" {
+      self.value = params.get(&self.name).cloned();
+      self.select_value();
+    }
+    self.error = errors.get(&self.name).map(|error| error.to_string());
+  }
+
+  /// Reflects the current value in checkbox and select controls.
+  fn select_value(&mut self) {
+    let value = self.value.as_deref();
+    self.checked = value == Some("true");
+    for option in &mut self.options {
+      option.selected = value == Some(option.value.as_str());
+    }
+  }
+}
+
+/// The choice of an existing service to deploy into, instead of creating a new one.
+#[derive(Serialize)]
+pub struct ServiceForm {
+  pub services: Vec<ServiceOption>,
+  /// The ID typed in, which takes precedence over the selected service
+  pub override_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ServiceOption {
+  pub id: String,
+  pub name: String,
+  pub selected: bool,
+}
+
+impl ServiceForm {
+  /// Defaults to creating a new service. After a rejected submission, the chosen service is
+  /// filled back in.
+  pub fn new(
+    services: Vec<FastlyServiceSummary>,
+    submission: Option<(&ActionParams, &ValidationError)>,
+  ) -> ServiceForm {
+    let params = submission.map(|(params, _)| params);
+    let param = |name: &str| params.and_then(|params| params.get(name));
+
+    let selected = param("service_id").map(|id| id.as_str());
+    ServiceForm {
+      services: services
+        .into_iter()
+        .map(|service| ServiceOption {
+          selected: selected == Some(service.id.as_str()),
+          id: service.id,
+          name: service.name,
+        })
+        .collect(),
+      override_id: param("service_id_override")
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty()),
+    }
+  }
+}
+
+/// The destination choices on the fork step.
+#[derive(Serialize)]
+pub struct ForkForm {
+  /// The user's own account followed by their organizations
+  pub owners: Vec<FormOption>,
+  pub name: String,
+  pub private: bool,
+  pub include_all_branches: bool,
+  pub owner_error: Option<String>,
+  pub name_error: Option<String>,
+  /// An existing repository at the destination that was created from the same template
+  pub reusable_nwo: Option<String>,
+}
+
+impl ForkForm {
+  /// Defaults to a public copy of the template under the user's account, with the template's
+  /// name. After a rejected submission, the entered choices are filled back in.
+  pub fn new(
+    user: &GitHubUser,
+    organizations: &[GitHubUser],
+    default_name: &str,
+    submission: Option<(&ActionParams, &ValidationError)>,
+  ) -> ForkForm {
+    let params = submission.map(|(params, _)| params);
+    let param = |name: &str| params.and_then(|params| params.get(name));
+
+    let owner = param("owner").map_or(user.login.as_str(), |owner| owner.as_str());
+    let owners = std::iter::once(user)
+      .chain(organizations.iter())
+      .map(|account| FormOption {
+        value: account.login.to_owned(),
+        selected: account.login == owner,
+      })
+      .collect();
+
+    let error = |field: &str| {
+      submission
+        .and_then(|(_, errors)| errors.get(field))
+        .map(|error| error.to_string())
+    };
+
+    ForkForm {
+      owners,
+      name: param("name").map_or(default_name, |name| name.as_str()).to_string(),
+      private: param("private").is_some_and(|value| value == "true"),
+      include_all_branches: param("include_all_branches").is_some_and(|value| value == "true"),
+      owner_error: error("owner"),
+      name_error: error("name"),
+      reusable_nwo: None,
+    }
+  }
+
+  /// The selected owner
+  pub fn owner(&self) -> Option<&str> {
+    self
+      .owners
+      .iter()
+      .find(|owner| owner.selected)
+      .map(|owner| owner.value.as_str())
+  }
+}
+
+/// Builds the inputs for every prompted backend and store item in the setup spec. After a
+/// rejected submission, the entered values are filled back in alongside their errors.
+pub fn fields(
+  spec: &DeployConfigSpec,
+  submission: Option<(&ActionParams, &ValidationError)>,
+) -> Vec<FormField> {
+  let mut fields: Vec<FormField> = spec
+    .backends
+    .iter()
+    .filter_map(|backend| {
+      backend
+        .prompt
+        .as_ref()
+        .map(|prompt| FormField::from_backend(backend, prompt))
+    })
+    .flatten()
+    .collect();
+
+  // Generated values are never shown
+  for (name, item) in spec.inputs() {
+    if item.input_type != InputType::Generated {
+      fields.push(FormField::from_item(name, item));
+    }
+  }
+
+  if let Some((params, errors)) = submission {
+    for field in &mut fields {
+      field.restore(params, errors);
+    }
+  }
+
+  fields
+}
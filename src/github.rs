@@ -1,17 +1,28 @@
 use anyhow::{bail, Result};
 use fastly::{
   http::{header, Method, StatusCode},
-  Dictionary, Request,
+  ConfigStore, Request,
 };
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use sealed_box::PublicKey;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
 use std::convert::TryInto;
+use std::thread;
+use std::time::{Duration, Instant};
 
 const AUTH_BACKEND: &str = "github.com";
 const API_BACKEND: &str = "api.github.com";
 const USER_AGENT: &str = "Quick Deploy (@kailan)";
 
+/// How many suffixed names to try when the chosen repository name is taken
+const MAX_NAME_SUGGESTIONS: usize = 5;
+
+/// How long to wait for a new repository to be populated, leaving the rest of the Compute
+/// request time budget for provisioning
+const READINESS_TIMEOUT: Duration = Duration::from_secs(20);
+const READINESS_INITIAL_DELAY: Duration = Duration::from_millis(500);
+const READINESS_MAX_DELAY: Duration = Duration::from_secs(4);
+
 pub type GitHubNWO = String;
 
 pub struct GitHubClient {
@@ -27,7 +38,7 @@ impl GitHubClient {
   }
 
   pub fn from_dictionary(dictionary_name: &str) -> Result<GitHubClient> {
-    let dictionary = Dictionary::open(dictionary_name);
+    let dictionary = ConfigStore::open(dictionary_name);
 
     Ok(GitHubClient {
       client_id: dictionary.get("client_id").unwrap(),
@@ -55,10 +66,10 @@ impl GitHubClient {
     req
   }
 
-  pub fn get_authorize_url(&self) -> String {
+  pub fn get_authorize_url(&self, state: &str) -> String {
     format!(
-      "https://github.com/login/oauth/authorize?client_id={}&scope=repo%20workflow",
-      &self.client_id
+      "https://github.com/login/oauth/authorize?client_id={}&scope=repo%20workflow%20read:org&state={}",
+      &self.client_id, state
     )
   }
 
@@ -76,7 +87,7 @@ impl GitHubClient {
   }
 
   pub fn fetch_user(&self) -> Result<Option<GitHubUser>> {
-    if self.user_access_token == None {
+    if self.user_access_token.is_none() {
       return Ok(None);
     }
 
@@ -89,10 +100,14 @@ impl GitHubClient {
   }
 
   pub fn fetch_repository(&self, nwo: &str) -> Result<Option<GitHubRepository>> {
-    let req = self.github_request(Request::new(
+    let mut req = self.github_request(Request::new(
       Method::GET,
       format!("https://api.github.com/repos/{}", nwo),
-    )).with_ttl(60 * 60 * 3); // The only data used from here is star + fork count so we can cache for a while
+    ));
+    // Authenticated lookups, such as checking for an existing repository, must not be cached
+    if self.user_access_token.is_none() {
+      req.set_ttl(60 * 60 * 3); // The only data used from here is star + fork count so we can cache for a while
+    }
     let mut resp = req.send(API_BACKEND)?;
 
     match resp.get_status() {
@@ -108,15 +123,54 @@ impl GitHubClient {
     }
   }
 
-  pub fn fork_repository(&self, nwo: &str, dst_name: &str) -> Result<GitHubRepository> {
-    let body = json!({"name": dst_name});
+  /// Finds a name that is free under the owner by appending a numeric suffix, giving up after a
+  /// few attempts.
+  pub fn suggest_repository_name(&self, owner: &str, name: &str) -> Result<Option<String>> {
+    for suffix in 2..=MAX_NAME_SUGGESTIONS + 1 {
+      let candidate = format!("{}-{}", name, suffix);
+      if !is_valid_repository_name(&candidate) {
+        break;
+      }
+      if self.fetch_repository(&format!("{}/{}", owner, candidate))?.is_none() {
+        return Ok(Some(candidate));
+      }
+    }
+    Ok(None)
+  }
+
+  /// Lists the organizations the user belongs to, which they may create repositories in.
+  pub fn list_organizations(&self) -> Result<Vec<GitHubUser>> {
+    let req = self.github_request(Request::new(Method::GET, "https://api.github.com/user/orgs"));
+    let mut resp = req.send(API_BACKEND)?;
+    match resp.get_status() {
+      StatusCode::OK => Ok(resp.take_body_json()?),
+      _ => bail!("Unable to list GitHub organizations: {}", resp.take_body_str()),
+    }
+  }
+
+  /// Creates a new repository from a template repository.
+  pub fn generate_repository(&self, nwo: &str, options: &GenerateRequest) -> Result<GitHubRepository> {
     let req = self.github_request(Request::new(
       Method::POST,
       format!("https://api.github.com/repos/{}/generate", nwo),
-    )).with_pass(true).with_body_json(&body).unwrap();
+    )).with_pass(true).with_body_json(options).unwrap();
     let mut resp = req.send(API_BACKEND)?;
     match resp.get_status() {
       StatusCode::CREATED => Ok(resp.take_body_json::<GitHubRepository>()?),
+      _ => bail!("Unable to generate GitHub repository from {}: {}", nwo, resp.take_body_str())
+    }
+  }
+
+  /// Forks a repository that is not a template. GitHub creates forks asynchronously, so the fork
+  /// must be waited for with `wait_until_ready` before reading from it.
+  pub fn fork_repository(&self, nwo: &str, options: &ForkRequest) -> Result<GitHubRepository> {
+    let req = self.github_request(Request::new(
+      Method::POST,
+      format!("https://api.github.com/repos/{}/forks", nwo),
+    )).with_pass(true).with_body_json(options).unwrap();
+    let mut resp = req.send(API_BACKEND)?;
+    match resp.get_status() {
+      StatusCode::ACCEPTED => Ok(resp.take_body_json::<GitHubRepository>()?),
       _ => bail!("Unable to fork GitHub repository {}: {}", nwo, resp.take_body_str())
     }
   }
@@ -136,16 +190,16 @@ impl GitHubClient {
     Ok(())
   }
 
-  pub fn get_file(&self, nwo: &str, path: &str) -> Result<Option<GitHubFile>> {
-    let req = self.github_request(Request::new(
-      Method::GET,
-      format!("https://api.github.com/repos/{}/contents/{}", nwo, path),
-    ));
+  /// Fetches a file at a branch, tag or commit, or from the default branch if none is given.
+  /// Updates to the file are made on the same ref.
+  pub fn get_file(&self, nwo: &str, path: &str, git_ref: Option<&str>) -> Result<Option<GitHubFile>> {
+    let req = self.github_request(Request::new(Method::GET, contents_url(nwo, path, git_ref)));
     let mut resp = req.send(API_BACKEND)?;
     match resp.get_status() {
       StatusCode::OK => {
         let mut file: GitHubFile = resp.take_body_json()?;
         file.content = String::from_utf8(base64::decode(file.content.replace('\n', ""))?)?;
+        file.branch = git_ref.map(|git_ref| git_ref.to_string());
         Ok(Some(file))
       }
 
@@ -160,6 +214,141 @@ impl GitHubClient {
     }
   }
 
+  /// Fetches the raw contents of a file, which need not be text, at a branch, tag or commit.
+  pub fn get_file_bytes(&self, nwo: &str, path: &str, git_ref: Option<&str>) -> Result<Option<Vec<u8>>> {
+    let mut req = self.github_request(Request::new(Method::GET, contents_url(nwo, path, git_ref)));
+    req.set_header(header::ACCEPT, "application/vnd.github.raw");
+    let mut resp = req.send(API_BACKEND)?;
+    match resp.get_status() {
+      StatusCode::OK => Ok(Some(resp.take_body_bytes())),
+
+      StatusCode::NOT_FOUND => Ok(None),
+
+      _ => bail!(
+        "Unable to fetch {} file from GitHub repository {}: {}",
+        path,
+        nwo,
+        resp.take_body_str()
+      ),
+    }
+  }
+
+  /// Polls with backoff until a file can be read from a new repository at the given ref, giving
+  /// up once the time budget is spent, and returns the file. GitHub populates new repositories
+  /// asynchronously, so this must be called before reading from one.
+  pub fn wait_until_ready(&self, nwo: &str, path: &str, git_ref: Option<&str>) -> Result<GitHubFile> {
+    self.poll(nwo, path, || self.get_file(nwo, path, git_ref))
+  }
+
+  /// Creates a branch at a commit, waiting until a new repository can accept it. Forks share
+  /// their parent's commits, but only once GitHub has finished creating them.
+  pub fn create_branch(&self, nwo: &str, branch: &str, sha: &str) -> Result<()> {
+    self.poll(nwo, &format!("commit {}", sha), || {
+      let req = self
+        .github_request(Request::new(
+          Method::POST,
+          format!("https://api.github.com/repos/{}/git/refs", nwo),
+        ))
+        .with_pass(true)
+        .with_body_json(&RefRequest {
+          git_ref: format!("refs/heads/{}", branch),
+          sha: sha.to_owned(),
+        })?;
+      let mut resp = req.send(API_BACKEND)?;
+
+      match resp.get_status() {
+        StatusCode::CREATED => Ok(Some(())),
+        // The repository or its commits are not there yet
+        StatusCode::NOT_FOUND | StatusCode::CONFLICT | StatusCode::UNPROCESSABLE_ENTITY => Ok(None),
+        _ => bail!(
+          "Unable to create branch {} in GitHub repository {}: {}",
+          branch,
+          nwo,
+          resp.take_body_str()
+        ),
+      }
+    })
+  }
+
+  /// Calls `ready` with backoff until it returns a value, giving up once the time budget is spent.
+  fn poll<T>(&self, nwo: &str, waiting_for: &str, mut ready: impl FnMut() -> Result<Option<T>>) -> Result<T> {
+    let started = Instant::now();
+    let mut delay = READINESS_INITIAL_DELAY;
+
+    loop {
+      if let Some(value) = ready()? {
+        return Ok(value);
+      }
+
+      if started.elapsed() + delay > READINESS_TIMEOUT {
+        bail!(
+          "github.com/{} does not contain {} yet. GitHub may still be creating it, so please try again in a moment.",
+          nwo,
+          waiting_for
+        );
+      }
+
+      println!("Waiting {}ms for {} in github.com/{}", delay.as_millis(), waiting_for, nwo);
+      thread::sleep(delay);
+      delay = std::cmp::min(delay * 2, READINESS_MAX_DELAY);
+    }
+  }
+
+  /// Resolves a branch, tag or commit to the SHA of its commit.
+  pub fn get_commit_sha(&self, nwo: &str, git_ref: &str) -> Result<Option<String>> {
+    let req = self.github_request(Request::new(
+      Method::GET,
+      format!(
+        "https://api.github.com/repos/{}/commits/{}",
+        nwo,
+        utf8_percent_encode(git_ref, NON_ALPHANUMERIC)
+      ),
+    ));
+    let mut resp = req.send(API_BACKEND)?;
+
+    match resp.get_status() {
+      StatusCode::OK => Ok(Some(resp.take_body_json::<GitHubCommit>()?.sha)),
+      StatusCode::NOT_FOUND | StatusCode::UNPROCESSABLE_ENTITY => Ok(None),
+      _ => bail!(
+        "Unable to fetch {} from GitHub repository {}: {}",
+        git_ref,
+        nwo,
+        resp.take_body_str()
+      ),
+    }
+  }
+
+  /// Whether a ref is a branch of the repository, rather than a tag, a commit or nothing at all
+  pub fn branch_exists(&self, nwo: &str, branch: &str) -> Result<bool> {
+    let mut req = self.github_request(Request::new(
+      Method::GET,
+      format!(
+        "https://api.github.com/repos/{}/branches/{}",
+        nwo,
+        utf8_percent_encode(branch, NON_ALPHANUMERIC)
+      ),
+    ));
+    // Authenticated lookups check repositories that are being set up, so must not be cached
+    if self.user_access_token.is_none() {
+      req.set_ttl(60 * 5);
+    } else {
+      req.set_pass(true);
+    }
+    let mut resp = req.send(API_BACKEND)?;
+
+    match resp.get_status() {
+      StatusCode::OK => Ok(true),
+      StatusCode::NOT_FOUND => Ok(false),
+      _ => bail!(
+        "Unable to fetch branch {} of GitHub repository {}: {}",
+        branch,
+        nwo,
+        resp.take_body_str()
+      ),
+    }
+  }
+
+  /// Creates or updates a file.
   pub fn upsert_file(&self, nwo: &str, file: &GitHubFile, content: &str) -> Result<()> {
     let mut req = self
       .github_request(Request::new(
@@ -174,9 +363,18 @@ impl GitHubClient {
       content: base64::encode(content),
       message: "Service provisioning via deploy.edgecompute.app".to_string(),
       sha: file.sha.to_owned(),
+      branch: file.branch.to_owned(),
     })?;
-    req.send(API_BACKEND)?;
-    Ok(())
+    let mut resp = req.send(API_BACKEND)?;
+    match resp.get_status() {
+      StatusCode::OK | StatusCode::CREATED => Ok(()),
+      _ => bail!(
+        "Unable to update {} in GitHub repository {}: {}",
+        file.path,
+        nwo,
+        resp.take_body_str()
+      ),
+    }
   }
 
   pub fn get_repository_public_key(&self, nwo: &str) -> Result<(PublicKey, String)> {
@@ -225,6 +423,114 @@ impl GitHubClient {
       Err(err) => bail!(err),
     }
   }
+
+  pub fn delete_secret(&self, nwo: &str, key: &str) -> Result<()> {
+    let req = self
+      .github_request(Request::new(
+        Method::DELETE,
+        format!(
+          "https://api.github.com/repos/{}/actions/secrets/{}",
+          nwo, key
+        ),
+      ))
+      .with_pass(true);
+    let mut resp = req.send(API_BACKEND)?;
+    match resp.get_status() {
+      StatusCode::NO_CONTENT | StatusCode::NOT_FOUND => Ok(()),
+      _ => bail!("Unable to delete secret {}: {}", key, resp.take_body_str()),
+    }
+  }
+}
+
+/// A repository to deploy from, addressed like GitHub's tree URLs: `{owner}/{repo}`, or
+/// `{owner}/{repo}/tree/{ref}/{subdir}` for an app that is not at the root of the default branch.
+/// Refs containing slashes cannot be told apart from the subdirectory, so are not supported.
+pub struct GitHubSource {
+  pub nwo: GitHubNWO,
+  pub git_ref: Option<String>,
+  pub subdir: Option<String>,
+}
+
+impl GitHubSource {
+  pub fn parse(path: &str) -> Option<GitHubSource> {
+    let parts: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+    if parts.iter().any(|part| part.is_empty() || *part == "." || *part == "..") {
+      return None;
+    }
+
+    match parts.as_slice() {
+      [owner, repo] => Some(GitHubSource {
+        nwo: format!("{}/{}", owner, repo),
+        git_ref: None,
+        subdir: None,
+      }),
+      [owner, repo, "tree", git_ref, subdir @ ..] => Some(GitHubSource {
+        nwo: format!("{}/{}", owner, repo),
+        git_ref: Some(git_ref.to_string()),
+        subdir: if subdir.is_empty() {
+          None
+        } else {
+          Some(subdir.join("/"))
+        },
+      }),
+      _ => None,
+    }
+  }
+
+  /// Resolves a path relative to the app's directory
+  pub fn path(&self, path: &str) -> String {
+    match self.subdir.as_ref() {
+      Some(subdir) => format!("{}/{}", subdir, path),
+      None => path.to_string(),
+    }
+  }
+
+  pub fn manifest_path(&self) -> String {
+    self.path("fastly.toml")
+  }
+}
+
+/// The contents API URL of a file, at a ref if one is given
+fn contents_url(nwo: &str, path: &str, git_ref: Option<&str>) -> String {
+  match git_ref {
+    Some(git_ref) => format!(
+      "https://api.github.com/repos/{}/contents/{}?ref={}",
+      nwo,
+      path,
+      utf8_percent_encode(git_ref, NON_ALPHANUMERIC)
+    ),
+    None => format!("https://api.github.com/repos/{}/contents/{}", nwo, path),
+  }
+}
+
+/// Checks a repository name against the characters GitHub allows.
+pub fn is_valid_repository_name(name: &str) -> bool {
+  !name.is_empty()
+    && name.len() <= 100
+    && name != "."
+    && name != ".."
+    && name
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Options for creating a repository from a template.
+#[derive(Serialize)]
+pub struct GenerateRequest {
+  pub owner: String,
+  pub name: String,
+  pub private: bool,
+  pub include_all_branches: bool,
+}
+
+/// Options for forking a repository.
+#[derive(Serialize)]
+pub struct ForkRequest {
+  /// The organization to fork into, instead of the user's account
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub organization: Option<String>,
+  pub name: String,
+  pub default_branch_only: bool,
 }
 
 #[derive(Deserialize)]
@@ -244,6 +550,8 @@ struct FileUpdateRequest {
   content: String,
   message: String,
   sha: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  branch: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -251,6 +559,9 @@ pub struct GitHubFile {
   path: String,
   pub content: String,
   sha: String,
+  /// The branch the file was read from, if not the default branch
+  #[serde(default)]
+  branch: Option<String>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -260,7 +571,47 @@ pub struct GitHubRepository {
   pub owner: GitHubUser,
   pub forks_count: i32,
   pub stargazers_count: i32,
-  pub is_template: bool
+  pub is_template: bool,
+  /// The template this repository was generated from, if any
+  pub template_repository: Option<GitHubRepositoryRef>,
+  /// The repository this one was forked from, if any
+  pub parent: Option<GitHubRepositoryRef>,
+}
+
+#[derive(Serialize)]
+struct RefRequest {
+  #[serde(rename = "ref")]
+  git_ref: String,
+  sha: String,
+}
+
+#[derive(Deserialize)]
+struct GitHubCommit {
+  sha: String,
+}
+
+impl GitHubRepository {
+  /// Whether this repository was forked from another, sharing its commits
+  pub fn is_fork_of(&self, nwo: &str) -> bool {
+    self
+      .parent
+      .as_ref()
+      .is_some_and(|parent| parent.full_name.eq_ignore_ascii_case(nwo))
+  }
+
+  /// Whether this repository was generated or forked from another
+  pub fn is_created_from(&self, nwo: &str) -> bool {
+    self
+      .template_repository
+      .iter()
+      .chain(self.parent.iter())
+      .any(|source| source.full_name.eq_ignore_ascii_case(nwo))
+  }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct GitHubRepositoryRef {
+  pub full_name: String,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -272,6 +623,7 @@ pub struct GitHubUser {
 #[derive(Deserialize)]
 pub struct AuthParams {
   pub code: String,
+  pub state: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -286,7 +638,7 @@ impl AccessTokenRequest {
     AccessTokenRequest {
       client_id: client.client_id.to_owned(),
       client_secret: client.client_secret.to_owned(),
-      code: code,
+      code,
     }
   }
 }
@@ -295,3 +647,44 @@ impl AccessTokenRequest {
 struct AccessTokenResponse {
   access_token: String,
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_repository_paths() {
+    let source = GitHubSource::parse("fastly/compute-starter-kit-rust-default").unwrap();
+    assert_eq!(source.nwo, "fastly/compute-starter-kit-rust-default");
+    assert_eq!(source.git_ref, None);
+    assert_eq!(source.subdir, None);
+    assert_eq!(source.manifest_path(), "fastly.toml");
+
+    let source = GitHubSource::parse("fastly/starters/").unwrap();
+    assert_eq!(source.nwo, "fastly/starters");
+  }
+
+  #[test]
+  fn parses_refs_and_subdirectories() {
+    let source = GitHubSource::parse("fastly/starters/tree/main").unwrap();
+    assert_eq!(source.nwo, "fastly/starters");
+    assert_eq!(source.git_ref.as_deref(), Some("main"));
+    assert_eq!(source.subdir, None);
+
+    let source = GitHubSource::parse("fastly/starters/tree/v2/apps/hello-world").unwrap();
+    assert_eq!(source.git_ref.as_deref(), Some("v2"));
+    assert_eq!(source.subdir.as_deref(), Some("apps/hello-world"));
+    assert_eq!(source.manifest_path(), "apps/hello-world/fastly.toml");
+    assert_eq!(source.path("static/index.html"), "apps/hello-world/static/index.html");
+  }
+
+  #[test]
+  fn rejects_other_paths() {
+    assert!(GitHubSource::parse("fastly").is_none());
+    assert!(GitHubSource::parse("fastly/starters/blob/main/README.md").is_none());
+    assert!(GitHubSource::parse("fastly/starters/tree").is_none());
+    assert!(GitHubSource::parse("fastly//starters").is_none());
+    assert!(GitHubSource::parse("fastly/starters/tree/main/../secrets").is_none());
+    assert!(GitHubSource::parse("fastly/starters/tree/./apps").is_none());
+  }
+}
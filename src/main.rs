@@ -1,68 +1,36 @@
 mod config;
+mod crypto;
+mod csrf;
+mod form;
 mod github;
+mod provision;
 mod scdn;
+mod session;
+mod state;
 mod templates;
 
 use anyhow::bail;
 
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use std::collections::HashMap;
 
-use toml_edit::{value, Document};
-
-use config::{DeployConfig, DeployConfigSpec};
-use github::{GitHubClient, GitHubNWO};
-use scdn::FastlyClient;
+use config::{DeployConfigSpec, FieldError, ValidationError};
+use crypto::Keyring;
+use form::{ForkForm, ServiceForm};
+use github::{
+    is_valid_repository_name, ForkRequest, GenerateRequest, GitHubClient, GitHubSource, GitHubUser,
+};
+use provision::Provisioner;
+use scdn::{FastlyClient, FastlyUser};
+use session::{KvSessionStore, Session};
+use state::{ApplicationState, DeploymentState, LoginState};
 use templates::{DeployContext, ErrorContext, IndexContext, SuccessContext, TemplateRenderer};
 
 use fastly::http::{header, Method, StatusCode};
 use fastly::{mime, Error, Request, Response};
 
-/// Stores the user's application state
-const STATE_COOKIE: &str = "__Secure-Deploy-Config";
-
-#[derive(Serialize, Deserialize)]
-struct ApplicationState {
-    pub login: LoginState,
-    pub deploy: DeploymentState,
-}
-
-#[derive(Serialize, Deserialize)]
-struct LoginState {
-    pub fastly_token: Option<String>,
-    pub github_token: Option<String>,
-}
-
-impl Default for LoginState {
-    fn default() -> LoginState {
-        LoginState {
-            fastly_token: None,
-            github_token: None,
-        }
-    }
-}
-
-#[derive(Serialize, Deserialize)]
-struct DeploymentState {
-    pub src: Option<GitHubNWO>,
-    pub dest: Option<GitHubNWO>,
-    pub fastly_service_id: Option<String>,
-    pub fastly_domain: Option<String>
-}
-
-impl Default for DeploymentState {
-    fn default() -> DeploymentState {
-        DeploymentState {
-            src: None,
-            dest: None,
-            fastly_service_id: None,
-            fastly_domain: None
-        }
-    }
-}
-
 #[fastly::main]
-fn main(req: Request) -> Result<Response, Error> {
+fn main(mut req: Request) -> Result<Response, Error> {
     println!(
         "Received request from {}: {} {}",
         req.get_client_ip_addr().unwrap(),
@@ -70,23 +38,50 @@ fn main(req: Request) -> Result<Response, Error> {
         req.get_path()
     );
 
+    // Static assets don't need a session
+    if let Some(resp) = serve_static(&req) {
+        return Ok(resp);
+    }
+
     // Initializes the template renderer
     let pages = TemplateRenderer::new();
 
     // Fetches the cookie header and parses it into a map
     let cookies = get_cookies(&req);
 
-    // Parse state cookie
-    let mut state: ApplicationState = match get_cookie(&cookies, STATE_COOKIE) {
-        Some(state_cookie) => {
-            serde_json::from_str(&String::from_utf8(base64::decode(state_cookie).unwrap()).unwrap())
-                .unwrap()
+    // Resumes the user's server-side session, starting a new one if necessary
+    let (mut session, mut state) = Session::resume(
+        Box::new(KvSessionStore::get_default()?),
+        Keyring::get_default()?,
+        &cookies,
+    )?;
+
+    // Clean up after a deployment that was abandoned when its session expired
+    if let Some(expired) = session.take_expired() {
+        abandon_deployment(&expired);
+    }
+
+    // Ensure the session has a token to protect forms against cross-site submission
+    let csrf_token = state
+        .csrf_token
+        .get_or_insert_with(crypto::random_token)
+        .to_string();
+
+    if req.get_method() == Method::POST {
+        if let Err(err) = csrf::verify_request(&mut req, &csrf_token) {
+            println!("Rejecting unverified form submission: {}", err);
+            let resp = Response::from_status(StatusCode::FORBIDDEN)
+                .with_content_type(mime::TEXT_HTML_UTF_8)
+                .with_body(pages.render_error_page(ErrorContext {
+                    message: "This form submission could not be verified. Please reload the page and try again.".to_string(),
+                    csrf_token,
+                    pending: provision::pending_resources(&state.deploy),
+                    rollback: None,
+                    retry_url: None,
+                }));
+            return session.save(resp, &state);
         }
-        None => ApplicationState {
-            login: LoginState::default(),
-            deploy: DeploymentState::default(),
-        },
-    };
+    }
 
     match (req.get_method(), req.get_path()) {
         (&Method::GET, "/") => {
@@ -101,25 +96,11 @@ fn main(req: Request) -> Result<Response, Error> {
             Ok(resp)
         }
 
-        (&Method::GET, "/style.css") => {
-            Ok(Response::from_body(include_str!("static/style.css"))
-                .with_content_type(mime::TEXT_CSS).with_header(header::CACHE_CONTROL, "public, max-age=1800"))
-        }
-
-        (&Method::GET, "/images/background.png") => Ok(Response::from_body(
-            include_bytes!("static/images/background.png").to_vec(),
-        )
-        .with_content_type(mime::IMAGE_PNG).with_header(header::CACHE_CONTROL, "public, max-age=1800")),
-
-        (&Method::GET, "/robots.txt") => Ok(Response::from_body(include_str!("static/robots.txt"))
-            .with_content_type(mime::TEXT_PLAIN)),
-
-        (&Method::GET, "/favicon.ico") => Ok(Response::from_body(include_bytes!("static/images/favicon.ico").to_vec())
-            .with_header(header::CONTENT_TYPE, "image/x-icon").with_header(header::CACHE_CONTROL, "public, max-age=1800")),
-
         (&Method::POST, "/auth/reset") => {
-            // Clear deploy state
+            // Clear login state and retire the session ID it was stored under
+            session.invalidate()?;
             state.login = LoginState::default();
+            state.csrf_token = Some(crypto::random_token());
 
             let resp = Response::from_status(StatusCode::FOUND).with_header(
                 header::LOCATION,
@@ -129,91 +110,216 @@ fn main(req: Request) -> Result<Response, Error> {
                 },
             );
 
-            Ok(update_state(resp, &state))
+            session.save(resp, &state)
         },
 
         (&Method::POST, "/deploy/reset") => {
+            // Remove anything a partial deployment has already created. Finished deployments
+            // are left alone and only forgotten.
+            let (gh, fastly_client) = get_clients(&state)?;
+            let report = Provisioner::new(&fastly_client, &gh).rollback(&state.deploy);
+
             // Clear deploy state
             state.deploy = DeploymentState::default();
 
-            let resp = Response::from_status(StatusCode::FOUND).with_header(header::LOCATION, "/");
+            if report.cleaned_up.is_empty() && report.failed.is_empty() {
+                let resp = Response::from_status(StatusCode::FOUND).with_header(header::LOCATION, "/");
+                return session.save(resp, &state);
+            }
+
+            let resp = Response::from_status(StatusCode::OK)
+                .with_content_type(mime::TEXT_HTML_UTF_8)
+                .with_body(pages.render_error_page(ErrorContext {
+                    message: "The deployment was cancelled.".to_string(),
+                    csrf_token,
+                    pending: provision::pending_resources(&state.deploy),
+                    rollback: Some(report),
+                    retry_url: None,
+                }));
 
-            Ok(update_state(resp, &state))
+            session.save(resp, &state)
         },
 
-        _ => match handle_action(req, state, &pages) {
+        _ => match handle_action(req, &mut state, &pages, &session) {
             Ok(resp) => Ok(resp),
-            Err(err) => Ok(Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
-                .with_content_type(mime::TEXT_HTML_UTF_8)
-                .with_body(pages.render_error_page(ErrorContext {
-                    message: err.to_string(),
-                }))),
+            Err(err) => {
+                let resp = Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .with_content_type(mime::TEXT_HTML_UTF_8)
+                    .with_body(pages.render_error_page(ErrorContext {
+                        message: err.to_string(),
+                        csrf_token,
+                        pending: provision::pending_resources(&state.deploy),
+                        rollback: None,
+                        retry_url: None,
+                    }));
+                session.save(resp, &state)
+            }
         },
     }
 }
 
 fn handle_action(
     mut req: Request,
-    mut state: ApplicationState,
+    state: &mut ApplicationState,
     pages: &TemplateRenderer,
+    session: &Session,
 ) -> Result<Response, Error> {
-    // Sets up a GitHub client with app credentials that we can use throughout the request
-    let mut gh = GitHubClient::get_default()?;
-
-    // Add a user access token to the GitHub client if defined
-    gh.user_access_token = match state.login.github_token.as_ref() {
-        Some(token) => Some(token.to_string()),
-        None => None,
-    };
+    let (mut gh, mut fastly_client) = get_clients(state)?;
 
     // Fetch the currently active GitHub user, if authenticated
     let gh_user = gh.fetch_user()?;
 
-    // Add a user access token to the Fastly client if defined
-    let mut fastly_client = match state.login.fastly_token.as_ref() {
-        Some(token) => FastlyClient::from_token(token.to_string()),
-        None => FastlyClient::new(),
-    };
-
     // Fetch the currently active Fastly user, if authenticated
     let fastly_user = fastly_client.fetch_user()?;
 
     match (req.get_method(), req.get_path()) {
         (&Method::POST, "/fork") => {
-            // Parse the form params to get repository
+            // Parse the form params to get repository and destination
             let params: ActionParams = req.take_body_form()?;
-            let nwo = &params["repository"];
+            let src_path = params["repository"].to_string();
+            let source = match GitHubSource::parse(&src_path) {
+                Some(source) => source,
+                None => bail!("Not a GitHub repository: {}", src_path),
+            };
+            let nwo = source.nwo.to_owned();
+
+            let user = match gh_user.as_ref() {
+                Some(user) => user,
+                None => bail!("Not signed in to GitHub"),
+            };
+
+            let is_branch = is_branch(&gh.anonymous(), &source)?;
+
+            // Continue with a repository that was already created from the same template
+            if let Some(dest_nwo) = params.get("reuse") {
+                let repo = match gh.fetch_repository(dest_nwo)? {
+                    Some(repo) if repo.is_created_from(&nwo) => repo,
+                    _ => bail!("github.com/{} was not created from github.com/{}", dest_nwo, nwo),
+                };
+                if let Some(git_ref) = source.git_ref.as_deref() {
+                    if is_branch && !gh.branch_exists(dest_nwo, git_ref)? {
+                        bail!("github.com/{} has no branch named {}", dest_nwo, git_ref);
+                    }
+                    if !is_branch && !repo.is_fork_of(&nwo) {
+                        bail!(
+                            "github.com/{} was not forked from github.com/{}, so does not contain {}",
+                            dest_nwo,
+                            nwo,
+                            git_ref
+                        );
+                    }
+                }
+
+                println!("Reusing {} for {}", dest_nwo, nwo);
+
+                let resp = Response::from_status(StatusCode::FOUND)
+                    .with_header(header::LOCATION, format!("/{}", src_path));
+
+                state.deploy.dest = Some(format!("{}+{}/{}", src_path, repo.owner.login, repo.name));
+                return session.save(resp, state);
+            }
+
+            let options = GenerateRequest {
+                owner: params.get("owner").unwrap_or(&user.login).to_string(),
+                name: match params.get("name") {
+                    Some(name) => name.trim().to_string(),
+                    None => nwo.split('/').next_back().unwrap().to_string(),
+                },
+                private: params.get("private").is_some_and(|value| value == "true"),
+                // A branch other than the default can only be deployed if it is copied too
+                include_all_branches: source.git_ref.is_some()
+                    || params
+                        .get("include_all_branches")
+                        .is_some_and(|value| value == "true"),
+            };
+
+            // Check the destination before asking GitHub to create anything
+            let errors = validate_destination(&gh, user, &options)?;
+            if !errors.errors.is_empty() {
+                println!("Fork rejected: {}", errors);
+
+                let ctx = deploy_context(
+                    &src_path,
+                    state,
+                    &gh,
+                    &fastly_client,
+                    gh_user,
+                    fastly_user,
+                    Some((&params, &errors)),
+                )?;
+
+                return Ok(Response::from_status(StatusCode::UNPROCESSABLE_ENTITY)
+                    .with_content_type(mime::TEXT_HTML_UTF_8)
+                    .with_body(pages.render_deploy_page(ctx)));
+            }
+
+            let src = match gh.anonymous().fetch_repository(&nwo)? {
+                Some(src) => src,
+                None => bail!("No repository was found at github.com/{}", nwo),
+            };
 
-            println!("Forking {}", nwo);
+            println!("Forking {} to {}/{}", nwo, options.owner, options.name);
 
-            // Fork the repository
-            match gh.fork_repository(&nwo, nwo.split('/').last().unwrap()) {
+            // Templates are copied with /generate, any other repository is forked. Generating
+            // rewrites the template's history, so tags and commits can only be deployed from a fork.
+            let result = if src.is_template && is_branch {
+                gh.generate_repository(&nwo, &options)
+            } else {
+                gh.fork_repository(
+                    &nwo,
+                    &ForkRequest {
+                        organization: if options.owner == user.login {
+                            None
+                        } else {
+                            Some(options.owner.to_owned())
+                        },
+                        name: options.name.to_owned(),
+                        default_branch_only: !options.include_all_branches,
+                    },
+                )
+            };
+
+            match result {
                 Ok(repo) => {
                     // Redirect back to deploy flow with the "Active-Fork" cookie set
                     let resp = Response::from_status(StatusCode::FOUND)
-                        .with_header(header::LOCATION, format!("/{}", nwo));
+                        .with_header(header::LOCATION, format!("/{}", src_path));
 
-                    state.deploy.dest = Some(format!("{}+{}/{}", nwo, repo.owner.login, repo.name));
-                    Ok(update_state(resp, &state))
+                    state.deploy.dest = Some(format!("{}+{}/{}", src_path, repo.owner.login, repo.name));
+                    session.save(resp, state)
                 }
                 Err(err) => bail!("Unable to fork repository: {}", err),
             }
         }
 
         (&Method::GET, "/deploy/status") => {
-            let service_id = match state.deploy.fastly_service_id {
+            // A failed deployment keeps what it created, which may be a service without a domain
+            // yet, so send the user back to the deploy page to resume it
+            if !provision::is_finished(&state.deploy) {
+                return Ok(Response::from_status(StatusCode::FOUND)
+                    .with_header(header::LOCATION, get_return_url(state)));
+            }
+
+            let service_id = match state.deploy.fastly_service_id.clone() {
                 Some(domain) => domain,
                 None => bail!("Fastly service has not been provisioned")
             };
 
-            let nwo = match state.deploy.dest.as_ref() {
-                Some(domain) => domain.split('+').last().expect("Invalid dest NWO pair"),
+            let nwo = match state.deploy.dest_nwo() {
+                Some(nwo) => nwo.to_string(),
                 None => bail!("GitHub repository has not been provisioned")
             };
 
-            let service_domain = state.deploy.fastly_domain.expect("Service is provisioned without domain");
+            let service_domain = match state.deploy.fastly_domain.clone() {
+                Some(domain) => domain,
+                None => bail!("Fastly service has no domain"),
+            };
 
-            let is_ready = fastly_client.check_service_deployment(&service_id)?;
+            // Versions older than the one provisioned don't include this deployment
+            let provisioned_version = state.deploy.fastly_service_version.unwrap_or(1);
+            let active_version =
+                fastly_client.check_service_deployment(&service_id, provisioned_version)?;
+            let is_ready = active_version.is_some();
 
             let resp = Response::from_status(StatusCode::NOT_IMPLEMENTED)
                 .with_content_type(mime::TEXT_HTML_UTF_8)
@@ -222,12 +328,13 @@ fn handle_action(
                     actions_url: format!("https://github.com/{}/actions", nwo),
                     repo_nwo: nwo.to_string(),
                     service_id,
-                    is_ready
+                    is_ready,
+                    active_version,
                 }));
 
             if is_ready {
                 state.deploy = DeploymentState::default();
-                Ok(update_state(resp, &state))
+                session.save(resp, state)
             } else {
                 Ok(resp)
             }
@@ -241,65 +348,58 @@ fn handle_action(
 
             println!("Deploying {}", nwo);
 
-            // Fetch fastly.toml file from repo
-            let manifest_file = match gh.get_file(&nwo, "fastly.toml")? {
-                Some(file) => file,
-                None => bail!("The source repository does not contain a fastly.toml file, so cannot be deployed via Quick Deploy")
-            };
-
-            println!("Fetched manifest");
-
-            // Parse manifest TOML in an editable fashion
-            let mut manifest = manifest_file.content.parse::<Document>()?;
-            println!("Parsed manifest");
-
-            // Deserialize manifest TOML to fetch setup spec
-            let config_spec = DeployConfigSpec::from_toml(&manifest_file.content)?;
+            let provisioner = Provisioner::new(&fastly_client, &gh);
 
-            // Generate a random name "quick-like-this"
-            let slug = format!(
-                "quick-{}",
-                parity_wordlist::random_phrase(2).replace(' ', "-")
-            );
+            match provisioner.run(session, state, &nwo, params.clone()) {
+                Ok(()) => {
+                    let resp = Response::from_status(StatusCode::FOUND)
+                        .with_header(header::LOCATION, "/deploy/status");
 
-            // Create Fastly service
-            let service = fastly_client.create_service(
-                &slug,
-                DeployConfig {
-                    spec: config_spec,
-                    params,
+                    session.save(resp, state)
+                }
+                Err(err) => match err.downcast::<ValidationError>() {
+                    // Nothing was provisioned, so show the form again with the problems highlighted
+                    Ok(errors) => {
+                        println!("Deployment rejected: {}", errors);
+
+                        let src_nwo = match state.deploy.src.clone() {
+                            Some(src_nwo) => src_nwo,
+                            None => bail!("No source repository has been chosen"),
+                        };
+                        let ctx = deploy_context(
+                            &src_nwo,
+                            state,
+                            &gh,
+                            &fastly_client,
+                            gh_user,
+                            fastly_user,
+                            Some((&params, &errors)),
+                        )?;
+
+                        let resp = Response::from_status(StatusCode::UNPROCESSABLE_ENTITY)
+                            .with_content_type(mime::TEXT_HTML_UTF_8)
+                            .with_body(pages.render_deploy_page(ctx));
+
+                        session.save(resp, state)
+                    }
+                    Err(err) => {
+                        // Completed steps are kept so that a retry can continue where this one stopped
+                        println!("Deployment failed: {}", err);
+
+                        let resp = Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+                            .with_content_type(mime::TEXT_HTML_UTF_8)
+                            .with_body(pages.render_error_page(ErrorContext {
+                                message: format!("Unable to deploy {}: {}", nwo, err),
+                                csrf_token: csrf_token(state),
+                                pending: provision::pending_resources(&state.deploy),
+                                rollback: None,
+                                retry_url: Some(get_return_url(state)),
+                            }));
+
+                        session.save(resp, state)
+                    }
                 },
-            )?;
-            state.deploy.fastly_service_id = Some(service.id.to_owned());
-            state.deploy.fastly_domain = Some(service.domain.expect("Domain was not created"));
-            println!("Service created (ID {})", service.id);
-
-            // Update service ID in manifest
-            manifest["service_id"] = value(service.id.to_owned());
-
-            // Generate output manifest
-            let output = manifest.to_string();
-            println!("Generated updated manifest");
-
-            println!("Enabling actions in forked repository");
-            gh.enable_actions(&nwo)?;
-
-            // Add Fastly API token as repository secret
-            println!("Creating FASTLY_API_TOKEN repository secret");
-            gh.create_secret(
-                &nwo,
-                "FASTLY_API_TOKEN",
-                &fastly_client.token.as_ref().unwrap(),
-            )?;
-
-            // Update manifest in GitHub repo
-            gh.upsert_file(&nwo, &manifest_file, &output)?;
-            println!("Manifest pushed to repository");
-
-            let resp = Response::from_status(StatusCode::FOUND)
-                .with_header(header::LOCATION, "/deploy/status");
-
-            Ok(update_state(resp, &state))
+            }
         }
 
         (&Method::POST, "/auth/fastly") => {
@@ -321,20 +421,49 @@ fn handle_action(
             );
             // Redirect to deploy flow with fastly token set
             let resp = Response::from_status(StatusCode::FOUND)
-                .with_header(header::LOCATION, get_return_url(&state));
+                .with_header(header::LOCATION, get_return_url(state));
 
             state.login.fastly_token = fastly_client.token;
 
-            Ok(update_state(resp, &state))
+            session.save(resp, state)
         }
 
         // Redirect to GitHub authorization flow
-        (&Method::GET, "/oauth/github") => Ok(Response::from_status(StatusCode::FOUND)
-            .with_header(header::LOCATION, gh.get_authorize_url())),
+        (&Method::GET, "/oauth/github") => {
+            // Bind the authorization flow to this session with a single-use nonce
+            let nonce = crypto::random_token();
+
+            let resp = Response::from_status(StatusCode::FOUND)
+                .with_header(header::LOCATION, gh.get_authorize_url(&nonce));
+
+            state.login.oauth_state = Some(nonce);
+
+            session.save(resp, state)
+        }
 
         // Handle callbacks from GitHub authorization flow
         (&Method::GET, "/oauth/github/callback") => match req.get_query::<github::AuthParams>() {
             Ok(auth) => {
+                // Ensure the callback belongs to an authorization flow started by this session
+                let is_valid_state = match (state.login.oauth_state.take(), auth.state.as_ref()) {
+                    (Some(expected), Some(received)) => crypto::constant_time_eq(&expected, received),
+                    _ => false,
+                };
+
+                if !is_valid_state {
+                    println!("Rejecting GitHub callback with mismatched state");
+                    let resp = Response::from_status(StatusCode::BAD_REQUEST)
+                        .with_content_type(mime::TEXT_HTML_UTF_8)
+                        .with_body(pages.render_error_page(ErrorContext {
+                            message: "The GitHub login could not be verified. It may have expired or been started from another browser. Please try logging in again.".to_string(),
+                            csrf_token: csrf_token(state),
+                            pending: provision::pending_resources(&state.deploy),
+                            rollback: None,
+                            retry_url: None,
+                        }));
+                    return session.save(resp, state);
+                }
+
                 // Request an access token using the received code
                 let token = gh.get_access_token_from_params(auth)?;
 
@@ -344,86 +473,35 @@ fn handle_action(
                 println!("User authenticated via GitHub");
                 // Return to deploy flow with gh token set
                 let resp = Response::from_status(StatusCode::FOUND)
-                    .with_header(header::LOCATION, get_return_url(&state));
+                    .with_header(header::LOCATION, get_return_url(state));
 
                 state.login.github_token = Some(token);
 
-                Ok(update_state(resp, &state))
+                session.save(resp, state)
             }
             Err(_) => Ok(Response::from_status(StatusCode::BAD_REQUEST)
-                .with_body_str("No auth 'code' param provided\n")),
+                .with_body_text_plain("No auth 'code' param provided\n")),
         },
 
-        // Serve deploy page on repository routes, e.g. "/abc/def"
-        (&Method::GET, _) if req.get_path().matches("/").count() == 2 => {
+        // Serve deploy page on repository routes, e.g. "/abc/def" or "/abc/def/tree/main/app"
+        (&Method::GET, _) if GitHubSource::parse(&req.get_path()[1..]).is_some() => {
             let path = req.get_path();
-            let src_nwo = &path[1..path.len()];
-
-            let dest_repository: Option<String> = match state.deploy.dest.as_ref() {
-                Some(state) => {
-                    let mut parts = state.split("+");
-                    if parts.next().unwrap() != src_nwo {
-                        None
-                    } else {
-                        Some(parts.next().unwrap().to_string())
-                    }
-                }
-                None => None,
-            };
+            let src_path = &path[1..path.len()];
 
-            println!("Fetching github.com/{}", src_nwo);
-
-            // Fetch the repo using the ANONYMOUS github client, so we only fetch public repos
-            // and are able to cache them.
-            let repo = match gh.anonymous().fetch_repository(src_nwo)? {
-                Some(repo) => repo,
-                None => bail!("No repository was found at github.com{}", path),
-            };
-
-            // Ensure repo is a template repository
-            if !repo.is_template {
-                bail!(
-                    "The chosen source is not a template repository: github.com{}",
-                    path
-                );
-            }
-
-            let can_deploy =
-                gh_user.is_some() && fastly_user.is_some() && dest_repository.is_some();
-
-            // Fetch manifest file from repo
-            let config_spec = if can_deploy {
-                match gh.anonymous().get_file(&src_nwo, "fastly.toml")? {
-                    Some(file) => Some(match DeployConfigSpec::from_toml(&file.content) {
-                        Ok(spec) => spec,
-                        Err(err) => bail!("Could not parse fastly.toml: {}", err),
-                    }),
-                    None => bail!("The repository does not contain a fastly.toml file."),
-                }
-            } else {
-                None
-            };
+            let ctx = deploy_context(src_path, state, &gh, &fastly_client, gh_user, fastly_user, None)?;
 
             let resp = Response::from_status(StatusCode::OK)
                 .with_content_type(mime::TEXT_HTML_UTF_8)
-                .with_body(pages.render_deploy_page(DeployContext {
-                    src: repo,
-                    can_deploy,
-                    can_fork: gh_user.is_some() && !dest_repository.is_some(),
-                    github_user: gh_user,
-                    fastly_user,
-                    dest_nwo: dest_repository,
-                    config_spec,
-                }));
+                .with_body(pages.render_deploy_page(ctx));
 
-            state.deploy.src = Some(src_nwo.to_string());
+            state.deploy.src = Some(src_path.to_string());
 
-            Ok(update_state(resp, &state))
+            session.save(resp, state)
         }
 
         // Catch all other requests and return a 404.
         _ => Ok(Response::from_status(StatusCode::NOT_FOUND)
-            .with_body_str("The page you requested could not be found\n")),
+            .with_body_text_plain("The page you requested could not be found\n")),
     }
 }
 
@@ -434,40 +512,299 @@ struct GenerateParams {
 
 type ActionParams = HashMap<String, String>;
 
+/// Builds the deploy page for a source repository path, e.g. `owner/repo/tree/main/app`. After a rejected submission, the entered
+/// values are filled back in alongside their validation errors.
+fn deploy_context(
+    src_path: &str,
+    state: &ApplicationState,
+    gh: &GitHubClient,
+    fastly_client: &FastlyClient,
+    gh_user: Option<GitHubUser>,
+    fastly_user: Option<FastlyUser>,
+    submission: Option<(&ActionParams, &ValidationError)>,
+) -> Result<DeployContext, Error> {
+    let dest_repository: Option<String> = match state.deploy.dest.as_ref() {
+        Some(state) => {
+            let mut parts = state.split("+");
+            if parts.next().unwrap() != src_path {
+                None
+            } else {
+                Some(parts.next().unwrap().to_string())
+            }
+        }
+        None => None,
+    };
+
+    let source = match GitHubSource::parse(src_path) {
+        Some(source) => source,
+        None => bail!("Not a GitHub repository: {}", src_path),
+    };
+    let src_nwo = source.nwo.as_str();
+
+    println!("Fetching github.com/{}", src_path);
+
+    // Fetch the repo using the ANONYMOUS github client, so we only fetch public repos
+    // and are able to cache them.
+    let repo = match gh.anonymous().fetch_repository(src_nwo)? {
+        Some(repo) => repo,
+        None => bail!("No repository was found at github.com/{}", src_nwo),
+    };
+
+    let can_deploy =
+        gh_user.is_some() && fastly_user.is_some() && dest_repository.is_some();
+
+    // Fetch manifest file from repo, at the chosen ref and directory
+    let manifest_path = source.manifest_path();
+    let config_spec = if can_deploy {
+        match gh
+            .anonymous()
+            .get_file(src_nwo, &manifest_path, source.git_ref.as_deref())?
+        {
+            Some(file) => Some(match DeployConfigSpec::from_toml(&file.content) {
+                Ok(spec) => spec,
+                Err(err) => bail!("Could not parse {}: {}", manifest_path, err),
+            }),
+            None => bail!("The repository does not contain a {} file.", manifest_path),
+        }
+    } else {
+        None
+    };
+
+    // Offer the user's organizations as owners of the new repository
+    let fork = match gh_user.as_ref() {
+        Some(user) if dest_repository.is_none() => {
+            let organizations = gh.list_organizations()?;
+            let mut fork = ForkForm::new(user, &organizations, &repo.name, submission);
+            check_existing_destination(gh, src_nwo, &mut fork)?;
+            Some(fork)
+        }
+        _ => None,
+    };
+
+    let fields = match config_spec.as_ref() {
+        Some(spec) => form::fields(spec, submission),
+        None => vec![],
+    };
+
+    // List existing services that the application could be deployed into instead
+    let services = if can_deploy {
+        fastly_client.list_services()?
+    } else {
+        vec![]
+    };
+
+    Ok(DeployContext {
+        src: repo,
+        src_path: src_path.to_string(),
+        src_ref: source.git_ref,
+        src_subdir: source.subdir,
+        can_deploy,
+        can_fork: fork.is_some(),
+        fork,
+        github_user: gh_user,
+        fastly_user,
+        dest_nwo: dest_repository,
+        resume_service_id: state.deploy.fastly_service_id.clone(),
+        service_form: ServiceForm::new(services, submission),
+        csrf_token: csrf_token(state),
+        config_spec,
+        fields,
+        has_errors: submission.is_some(),
+    })
+}
+
+/// Whether the ref being deployed is a branch, which is copied along with the repository. Tags
+/// and commits are deployed from a new branch at the same commit, so the ref must exist.
+fn is_branch(gh: &GitHubClient, source: &GitHubSource) -> Result<bool, Error> {
+    match source.git_ref.as_deref() {
+        None => Ok(true),
+        Some(git_ref) if gh.branch_exists(&source.nwo, git_ref)? => Ok(true),
+        Some(git_ref) => match gh.get_commit_sha(&source.nwo, git_ref)? {
+            Some(_) => Ok(false),
+            None => bail!("github.com/{} has no branch, tag or commit named {}", source.nwo, git_ref),
+        },
+    }
+}
+
+/// Checks that a new repository can be created with the chosen owner and name.
+fn validate_destination(
+    gh: &GitHubClient,
+    user: &GitHubUser,
+    options: &GenerateRequest,
+) -> Result<ValidationError, Error> {
+    let mut errors = vec![];
+
+    if options.owner != user.login
+        && !gh
+            .list_organizations()?
+            .iter()
+            .any(|org| org.login == options.owner)
+    {
+        errors.push(FieldError::new(
+            "owner".to_string(),
+            "Choose your account or one of your organizations",
+        ));
+    }
+
+    if !is_valid_repository_name(&options.name) {
+        errors.push(FieldError::new(
+            "name".to_string(),
+            "Use up to 100 letters, digits, hyphens, underscores and periods",
+        ));
+    } else if errors.is_empty() {
+        let dest_nwo = format!("{}/{}", options.owner, options.name);
+        if gh.fetch_repository(&dest_nwo)?.is_some() {
+            errors.push(FieldError::new(
+                "name".to_string(),
+                &format!("A repository already exists at github.com/{}", dest_nwo),
+            ));
+        }
+    }
+
+    Ok(ValidationError { errors })
+}
+
+/// Looks for a repository at the destination shown on the fork step. One created from the same
+/// template can be reused, otherwise a free name is suggested in place of the taken one.
+fn check_existing_destination(
+    gh: &GitHubClient,
+    src_nwo: &str,
+    fork: &mut ForkForm,
+) -> Result<(), Error> {
+    let owner = match fork.owner() {
+        Some(owner) => owner.to_string(),
+        None => return Ok(()),
+    };
+    if !is_valid_repository_name(&fork.name) {
+        return Ok(());
+    }
+
+    let dest_nwo = format!("{}/{}", owner, fork.name);
+    let existing = match gh.fetch_repository(&dest_nwo)? {
+        Some(existing) => existing,
+        None => return Ok(()),
+    };
+
+    if existing.is_created_from(src_nwo) {
+        fork.name_error = Some(format!(
+            "github.com/{} was already created from this repository. Use it, or choose another name.",
+            dest_nwo
+        ));
+        fork.reusable_nwo = Some(dest_nwo);
+    } else if let Some(name) = gh.suggest_repository_name(&owner, &fork.name)? {
+        fork.name_error = Some(format!(
+            "github.com/{} already exists, so {} has been suggested instead.",
+            dest_nwo, name
+        ));
+        fork.name = name;
+    }
+
+    Ok(())
+}
+
+/// Rolls back an unfinished deployment that will no longer be retried.
+fn abandon_deployment(state: &ApplicationState) {
+    if provision::pending_resources(&state.deploy).is_empty() {
+        return;
+    }
+
+    println!("Rolling back abandoned deployment");
+    match get_clients(state) {
+        Ok((gh, fastly_client)) => {
+            Provisioner::new(&fastly_client, &gh).rollback(&state.deploy);
+        }
+        Err(err) => println!("Unable to roll back abandoned deployment: {}", err),
+    }
+}
+
 fn get_return_url(state: &ApplicationState) -> String {
     format!("/{}", state.deploy.src.as_ref().unwrap_or(&"".to_string()))
 }
 
-fn update_state(resp: Response, state: &ApplicationState) -> Response {
-    resp.with_header(
-        header::SET_COOKIE,
-        format!(
-            "{}={}; Secure; HttpOnly; Path=/;",
-            STATE_COOKIE,
-            base64::encode(serde_json::to_string(state).unwrap())
-        ),
-    )
+/// Sets up GitHub and Fastly clients, authenticated as the user where tokens are available
+fn get_clients(state: &ApplicationState) -> Result<(GitHubClient, FastlyClient), Error> {
+    // Sets up a GitHub client with app credentials that we can use throughout the request
+    let mut gh = GitHubClient::get_default()?;
+
+    // Add a user access token to the GitHub client if defined
+    gh.user_access_token = state.login.github_token.as_ref().map(|token| token.to_string());
+
+    // Add a user access token to the Fastly client if defined
+    let fastly_client = match state.login.fastly_token.as_ref() {
+        Some(token) => FastlyClient::from_token(token.to_string()),
+        None => FastlyClient::new(),
+    };
+
+    Ok((gh, fastly_client))
 }
 
-fn get_cookie(cookies: &HashMap<&str, &str>, key: &str) -> Option<String> {
-    match cookies.get(key) {
-        Some(value) => Some(value.to_string()),
-        None => None,
+fn csrf_token(state: &ApplicationState) -> String {
+    state.csrf_token.clone().unwrap_or_default()
+}
+
+fn serve_static(req: &Request) -> Option<Response> {
+    match (req.get_method(), req.get_path()) {
+        (&Method::GET, "/style.css") => {
+            Some(Response::from_body(include_str!("static/style.css"))
+                .with_content_type(mime::TEXT_CSS).with_header(header::CACHE_CONTROL, "public, max-age=1800"))
+        }
+
+        (&Method::GET, "/images/background.png") => Some(Response::from_body(
+            include_bytes!("static/images/background.png").to_vec(),
+        )
+        .with_content_type(mime::IMAGE_PNG).with_header(header::CACHE_CONTROL, "public, max-age=1800")),
+
+        (&Method::GET, "/robots.txt") => Some(Response::from_body(include_str!("static/robots.txt"))
+            .with_content_type(mime::TEXT_PLAIN)),
+
+        (&Method::GET, "/favicon.ico") => Some(Response::from_body(include_bytes!("static/images/favicon.ico").to_vec())
+            .with_header(header::CONTENT_TYPE, "image/x-icon").with_header(header::CACHE_CONTROL, "public, max-age=1800")),
+
+        _ => None,
     }
 }
 
 fn get_cookies(req: &Request) -> HashMap<&str, &str> {
     match req.get_header(header::COOKIE) {
-        Some(cookie) => parse_cookies_to_map(cookie.to_str().unwrap()),
+        // A header that is not valid ASCII is treated as if no cookies were sent
+        Some(cookie) => match cookie.to_str() {
+            Ok(cookie) => parse_cookies_to_map(cookie),
+            Err(_) => HashMap::new(),
+        },
         None => HashMap::new(),
     }
 }
 
+/// Parses a Cookie header, skipping malformed pairs rather than failing the request
 fn parse_cookies_to_map(value: &str) -> HashMap<&str, &str> {
     let mut jar = HashMap::new();
     for cookie in value.split(';') {
-        let mut split = cookie.trim().split("=");
-        jar.insert(split.next().unwrap(), split.next().unwrap());
+        let mut split = cookie.trim().splitn(2, '=');
+        if let (Some(name), Some(value)) = (split.next(), split.next()) {
+            if !name.is_empty() {
+                jar.insert(name, value);
+            }
+        }
     }
     jar
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cookies() {
+        let jar = parse_cookies_to_map("a=1; b=two=2");
+        assert_eq!(jar.get("a"), Some(&"1"));
+        assert_eq!(jar.get("b"), Some(&"two=2"));
+    }
+
+    #[test]
+    fn skips_malformed_cookies() {
+        let jar = parse_cookies_to_map("a=1; ; flag; =orphan; b=2; ");
+        assert_eq!(jar.len(), 2);
+        assert_eq!(jar.get("a"), Some(&"1"));
+        assert_eq!(jar.get("b"), Some(&"2"));
+    }
+}
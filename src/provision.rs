@@ -0,0 +1,478 @@
+use crate::config::{DeployConfig, DeployConfigSpec, InputType, Variables};
+use crate::github::GitHubClient;
+use crate::scdn::{FastlyClient, StoreKind};
+use crate::session::Session;
+use crate::state::{ApplicationState, DeploymentState};
+use crate::ActionParams;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use toml_edit::{value, Document};
+
+/// Name of the repository secret holding the user's Fastly API token
+const TOKEN_SECRET: &str = "FASTLY_API_TOKEN";
+
+/// A step of the deployment pipeline. Completed steps are recorded in the session so that a
+/// retried deployment continues from the first incomplete step.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeployStep {
+  ServiceCreated,
+  DomainAdded,
+  BackendsCreated,
+  LogEndpointsCreated,
+  DictionariesCreated,
+  ConfigStoresCreated,
+  KvStoresCreated,
+  SecretStoresCreated,
+  ActionsEnabled,
+  SecretSet,
+  ManifestPushed,
+}
+
+/// A resource created while provisioning a deployment, with enough detail to undo it.
+pub enum Provisioned<'a> {
+  Service { id: &'a str },
+  Store { kind: StoreKind, id: &'a str },
+  RepositorySecret { nwo: &'a str, name: &'a str },
+}
+
+impl Provisioned<'_> {
+  fn describe(&self) -> String {
+    match self {
+      Provisioned::Service { id } => format!("Fastly service {}", id),
+      Provisioned::Store { kind, id } => format!("Fastly {} store {}", kind.path(), id),
+      Provisioned::RepositorySecret { nwo, name } => {
+        format!("{} secret in github.com/{}", name, nwo)
+      }
+    }
+  }
+}
+
+/// The outcome of undoing a deployment.
+#[derive(Serialize)]
+pub struct RollbackReport {
+  pub cleaned_up: Vec<String>,
+  pub failed: Vec<String>,
+}
+
+/// Provisions a Fastly service for a repository and connects the two.
+pub struct Provisioner<'a> {
+  fastly: &'a FastlyClient,
+  gh: &'a GitHubClient,
+}
+
+impl<'a> Provisioner<'a> {
+  pub fn new(fastly: &'a FastlyClient, gh: &'a GitHubClient) -> Provisioner<'a> {
+    Provisioner { fastly, gh }
+  }
+
+  /// Runs every step that has not yet completed, saving progress to the session after each one.
+  pub fn run(
+    &self,
+    session: &Session,
+    state: &mut ApplicationState,
+    nwo: &str,
+    params: ActionParams,
+  ) -> Result<()> {
+    let source = match state.deploy.source() {
+      Some(source) => source,
+      None => bail!("No source repository has been chosen"),
+    };
+
+    let branch = match source.git_ref.as_deref() {
+      Some(git_ref) => Some(self.deploy_branch(nwo, &source.nwo, git_ref)?),
+      None => None,
+    };
+    let branch = branch.as_deref();
+
+    // Fetch fastly.toml file from repo, at the same path as in the source. The repository may
+    // still be being generated from its template, so wait until the file is there.
+    let manifest_path = source.manifest_path();
+    let manifest_file = self.gh.wait_until_ready(nwo, &manifest_path, branch)?;
+
+    println!("Fetched manifest");
+
+    // Deserialize manifest TOML to fetch setup spec
+    let mut deploy = DeployConfig {
+      spec: DeployConfigSpec::from_toml(&manifest_file.content)?,
+      params,
+      variables: Variables::default(),
+      generated: BTreeMap::new(),
+    };
+
+    deploy.variables.set("github.repository", nwo);
+    if let Some(user) = self.gh.fetch_user()? {
+      deploy.variables.set("github.login", &user.login);
+    }
+
+    // Validate user-supplied values before any resources are created
+    deploy.validate()?;
+
+    // Generate random values once, so that a retry writes the same ones
+    let generated: Vec<(String, String)> = deploy
+      .spec
+      .inputs()
+      .into_iter()
+      .filter(|(_, item)| item.input_type == InputType::Generated)
+      .map(|(field, item)| {
+        let value = state
+          .deploy
+          .generated_values
+          .entry(field.to_owned())
+          .or_insert_with(|| item.generate())
+          .to_owned();
+        (field, value)
+      })
+      .collect();
+    deploy.generated.extend(generated);
+
+    // Generate a random name "quick-like-this", keeping it across retries
+    let slug = state
+      .deploy
+      .slug
+      .get_or_insert_with(|| format!("quick-{}", parity_wordlist::random_phrase(2).replace(' ', "-")))
+      .to_string();
+
+    if !is_complete(&state.deploy, DeployStep::ServiceCreated) {
+      match target_service_id(&deploy.params) {
+        // Stage changes to an existing service on a new draft version
+        Some(service_id) => {
+          let version = self.fastly.clone_service_version(&service_id)?;
+          state.deploy.fastly_service_id = Some(service_id);
+          state.deploy.fastly_service_version = Some(version);
+          state.deploy.existing_service = true;
+        }
+        // New services start with an editable version 1. The service is recorded as soon as it
+        // exists, so that a later failure neither orphans it nor creates another on retry.
+        None => {
+          let service = self.fastly.create_service(&slug)?;
+          state.deploy.fastly_service_id = Some(service.id);
+          state.deploy.fastly_service_version = Some(1);
+        }
+      }
+      self.complete(session, state, DeployStep::ServiceCreated)?;
+    }
+
+    let (service_id, version) = match (
+      state.deploy.fastly_service_id.clone(),
+      state.deploy.fastly_service_version,
+    ) {
+      (Some(id), Some(version)) => (id, version),
+      _ => bail!("Fastly service has not been provisioned"),
+    };
+    deploy.variables.set("service_id", &service_id);
+
+    if !is_complete(&state.deploy, DeployStep::DomainAdded) {
+      // Existing services keep the domain they already serve
+      let existing_domain = if state.deploy.existing_service {
+        self.fastly.list_domains(&service_id, version)?.into_iter().next()
+      } else {
+        None
+      };
+
+      let domain = match existing_domain {
+        Some(domain) => domain,
+        None => self
+          .fastly
+          .create_domain(&service_id, version, &format!("{}.edgecompute.app", slug))?,
+      };
+      state.deploy.fastly_domain = Some(domain.name);
+      self.complete(session, state, DeployStep::DomainAdded)?;
+    }
+
+    match state.deploy.fastly_domain.as_ref() {
+      Some(domain) => deploy.variables.set("domain", domain),
+      None => bail!("No domain has been added to the Fastly service"),
+    }
+
+    if !is_complete(&state.deploy, DeployStep::BackendsCreated) {
+      deploy.resolve_backends()?;
+      self
+        .fastly
+        .create_backends(&service_id, version, &deploy.spec.backends)?;
+      self.complete(session, state, DeployStep::BackendsCreated)?;
+    }
+
+    if !is_complete(&state.deploy, DeployStep::LogEndpointsCreated) {
+      for (name, endpoint) in &deploy.spec.log_endpoints {
+        let fields = deploy.log_endpoint_fields(name, endpoint)?;
+        self
+          .fastly
+          .create_log_endpoint(&service_id, version, endpoint.provider, name, fields)?;
+      }
+      self.complete(session, state, DeployStep::LogEndpointsCreated)?;
+    }
+
+    if !is_complete(&state.deploy, DeployStep::DictionariesCreated) {
+      self.fastly.create_dictionaries(&service_id, version, &deploy)?;
+      self.complete(session, state, DeployStep::DictionariesCreated)?;
+    }
+
+    if !is_complete(&state.deploy, DeployStep::ConfigStoresCreated) {
+      for (name, store) in &deploy.spec.config_stores {
+        let items = deploy.config_store_items(name, store)?;
+        let store_id = self.link_store(
+          &mut state.deploy.config_store_ids,
+          StoreKind::Config,
+          &service_id,
+          version,
+          &slug,
+          name,
+        )?;
+        self.fastly.upsert_config_items(&store_id, items)?;
+      }
+      self.complete(session, state, DeployStep::ConfigStoresCreated)?;
+    }
+
+    if !is_complete(&state.deploy, DeployStep::KvStoresCreated) {
+      for (name, store) in &deploy.spec.kv_stores {
+        let store_id = self.link_store(
+          &mut state.deploy.kv_store_ids,
+          StoreKind::Kv,
+          &service_id,
+          version,
+          &slug,
+          name,
+        )?;
+
+        for item in &store.items {
+          let key = &item.key;
+          let value = match (&item.value, &item.file) {
+            (Some(value), _) => deploy.variables.interpolate(value)?.into_bytes(),
+            (None, Some(path)) => {
+              // Like the fastly CLI, paths are relative to the manifest rather than the repository
+              let path = source.path(path);
+              match self.gh.get_file_bytes(nwo, &path, branch)? {
+                Some(contents) => contents,
+                None => bail!("The file {} for KV store {} does not exist in the repository", path, name),
+              }
+            }
+            (None, None) => bail!("No value provided for item {} of KV store {}", key, name),
+          };
+          self.fastly.put_kv_entry(&store_id, key, value)?;
+        }
+        println!("Populated KV store {} with {} items", name, store.items.len());
+      }
+      self.complete(session, state, DeployStep::KvStoresCreated)?;
+    }
+
+    if !is_complete(&state.deploy, DeployStep::SecretStoresCreated) {
+      for (name, entries) in deploy.secret_store_entries()? {
+        let store_id = self.link_store(
+          &mut state.deploy.secret_store_ids,
+          StoreKind::Secret,
+          &service_id,
+          version,
+          &slug,
+          &name,
+        )?;
+        for (key, value) in entries {
+          self.fastly.put_secret(&store_id, &key, &value)?;
+        }
+      }
+      self.complete(session, state, DeployStep::SecretStoresCreated)?;
+    }
+
+    println!("Service provisioned (ID {})", service_id);
+
+    if !is_complete(&state.deploy, DeployStep::ActionsEnabled) {
+      println!("Enabling actions in forked repository");
+      self.gh.enable_actions(nwo)?;
+      self.complete(session, state, DeployStep::ActionsEnabled)?;
+    }
+
+    if !is_complete(&state.deploy, DeployStep::SecretSet) {
+      // Add Fastly API token as repository secret, followed by any generated values
+      let mut secrets = vec![(TOKEN_SECRET, self.fastly.token.as_deref().unwrap())];
+      for (field, item) in deploy.spec.inputs() {
+        if let (Some(name), Some(value)) = (&item.github_secret, deploy.generated.get(&field)) {
+          secrets.push((name.as_str(), value.as_str()));
+        }
+      }
+
+      for (name, value) in secrets {
+        println!("Creating {} repository secret", name);
+        self.gh.create_secret(nwo, name, value)?;
+
+        // Each secret is recorded as soon as it exists, so that it is rolled back if a later one fails
+        if !state.deploy.repository_secrets.iter().any(|secret| secret == name) {
+          state.deploy.repository_secrets.push(name.to_owned());
+          session.persist(state)?;
+        }
+      }
+      self.complete(session, state, DeployStep::SecretSet)?;
+    }
+
+    if !is_complete(&state.deploy, DeployStep::ManifestPushed) {
+      // Update service ID in manifest, parsing it in an editable fashion
+      let mut manifest = manifest_file.content.parse::<Document>()?;
+      manifest["service_id"] = value(service_id.to_owned());
+      let output = manifest.to_string();
+      println!("Generated updated manifest");
+
+      // Update manifest in GitHub repo
+      self.gh.upsert_file(nwo, &manifest_file, &output)?;
+      self.complete(session, state, DeployStep::ManifestPushed)?;
+      println!("Manifest pushed to repository");
+    }
+
+    Ok(())
+  }
+
+  /// Finds the branch of the new repository to deploy from. Branches of the source are copied
+  /// along with it, while tags and commits get a new `deploy/<ref>` branch at the same commit.
+  fn deploy_branch(&self, nwo: &str, src_nwo: &str, git_ref: &str) -> Result<String> {
+    if self.gh.anonymous().branch_exists(src_nwo, git_ref)? {
+      return Ok(git_ref.to_string());
+    }
+
+    let branch = format!("deploy/{}", git_ref);
+    if !self.gh.branch_exists(nwo, &branch)? {
+      let sha = match self.gh.anonymous().get_commit_sha(src_nwo, git_ref)? {
+        Some(sha) => sha,
+        None => bail!("github.com/{} has no branch, tag or commit named {}", src_nwo, git_ref),
+      };
+      self.gh.create_branch(nwo, &branch, &sha)?;
+      println!("Created branch {} at {}", branch, sha);
+    }
+
+    Ok(branch)
+  }
+
+  /// Undoes every completed step of a deployment, most recent first.
+  pub fn rollback(&self, deploy: &DeploymentState) -> RollbackReport {
+    let mut report = RollbackReport {
+      cleaned_up: vec![],
+      failed: vec![],
+    };
+
+    // A finished deployment is in use, so is never undone
+    if is_finished(deploy) {
+      return report;
+    }
+
+    for resource in provisioned_resources(deploy).into_iter().rev() {
+      let result = match &resource {
+        Provisioned::Service { id } => self.fastly.delete_service(id),
+        Provisioned::Store { kind, id } => self.fastly.delete_store(*kind, id),
+        Provisioned::RepositorySecret { nwo, name } => self.gh.delete_secret(nwo, name),
+      };
+
+      match result {
+        Ok(()) => {
+          println!("Rolled back {}", resource.describe());
+          report.cleaned_up.push(resource.describe());
+        }
+        Err(err) => {
+          println!("Unable to roll back {}: {}", resource.describe(), err);
+          report
+            .failed
+            .push(format!("{} ({})", resource.describe(), err));
+        }
+      }
+    }
+
+    report
+  }
+
+  /// Finds the deployment's store of a kind, creating it if necessary, and links it to the service
+  /// version under the name the application uses. Returns the store's ID.
+  fn link_store(
+    &self,
+    created_ids: &mut Vec<String>,
+    kind: StoreKind,
+    service_id: &str,
+    version: i32,
+    slug: &str,
+    link_name: &str,
+  ) -> Result<String> {
+    // Stores belong to the whole account, so each deployment gets its own
+    let store_name = format!("{}-{}", slug, link_name);
+
+    let store_id = match self.fastly.find_store(kind, &store_name)?.and_then(|store| store.id) {
+      Some(id) => id,
+      None => {
+        let id = match self.fastly.create_store(kind, &store_name)?.id {
+          Some(id) => id,
+          None => bail!("Store {} was created without an ID", store_name),
+        };
+        created_ids.push(id.to_owned());
+        id
+      }
+    };
+
+    self.fastly.link_resource(service_id, version, &store_id, link_name)?;
+    Ok(store_id)
+  }
+
+  fn complete(&self, session: &Session, state: &mut ApplicationState, step: DeployStep) -> Result<()> {
+    state.deploy.completed_steps.push(step);
+    session.persist(state)
+  }
+}
+
+/// The existing service chosen on the deploy form, if any. A typed ID takes precedence.
+fn target_service_id(params: &ActionParams) -> Option<String> {
+  ["service_id_override", "service_id"]
+    .iter()
+    .filter_map(|key| params.get(*key))
+    .map(|id| id.trim())
+    .find(|id| !id.is_empty())
+    .map(|id| id.to_string())
+}
+
+/// Describes what an unfinished deployment has created so far, which rolling it back would remove.
+pub fn pending_resources(deploy: &DeploymentState) -> Vec<String> {
+  if is_finished(deploy) {
+    return vec![];
+  }
+
+  provisioned_resources(deploy)
+    .iter()
+    .map(|resource| resource.describe())
+    .collect()
+}
+
+/// Whether every step of a deployment has completed
+pub fn is_finished(deploy: &DeploymentState) -> bool {
+  is_complete(deploy, DeployStep::ManifestPushed)
+}
+
+fn is_complete(deploy: &DeploymentState, step: DeployStep) -> bool {
+  deploy.completed_steps.contains(&step)
+}
+
+/// Lists the resources created by a deployment. Rolling back undoes them in reverse order.
+fn provisioned_resources(deploy: &DeploymentState) -> Vec<Provisioned<'_>> {
+  let mut resources = vec![];
+
+  // Stores outlive the services they are linked to. Listing them first means they are only
+  // deleted once the service linking them has gone.
+  let stores = [
+    (StoreKind::Config, &deploy.config_store_ids),
+    (StoreKind::Kv, &deploy.kv_store_ids),
+    (StoreKind::Secret, &deploy.secret_store_ids),
+  ];
+  for (kind, ids) in stores.iter() {
+    for id in ids.iter() {
+      resources.push(Provisioned::Store { kind: *kind, id });
+    }
+  }
+
+  // Services that existed before the deployment are never deleted. Their draft version is left
+  // unactivated, which is harmless.
+  if let Some(id) = deploy.fastly_service_id.as_ref() {
+    if !deploy.existing_service {
+      resources.push(Provisioned::Service { id });
+    }
+  }
+
+  if let Some(nwo) = deploy.dest_nwo() {
+    for name in &deploy.repository_secrets {
+      resources.push(Provisioned::RepositorySecret { nwo, name });
+    }
+  }
+
+  resources
+}
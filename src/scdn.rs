@@ -1,12 +1,15 @@
-use crate::config::BackendSpec;
+use crate::config::{BackendSpec, HealthcheckSpec};
 use crate::config::DeployConfig;
+use crate::config::LogProvider;
 use anyhow::{bail, Result};
 use fastly::http::StatusCode;
 use fastly::{
   http::{header, Method},
   Request,
 };
-use serde::{Deserialize, Serialize};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 const USER_AGENT: &str = "Quick Deploy (@kailan)";
 const API_BACKEND: &str = "api.fastly.com";
@@ -26,7 +29,7 @@ impl FastlyClient {
   }
 
   pub fn fastly_request(&self, req: Request) -> Result<Request> {
-    if self.token == None {
+    if self.token.is_none() {
       bail!("No Fastly API token set");
     }
 
@@ -39,7 +42,7 @@ impl FastlyClient {
   }
 
   pub fn fetch_user(&self) -> Result<Option<FastlyUser>> {
-    if self.token == None {
+    if self.token.is_none() {
       return Ok(None);
     }
 
@@ -54,13 +57,11 @@ impl FastlyClient {
     }
   }
 
-  pub fn create_service(&self, slug: &str, mut deploy: DeployConfig) -> Result<FastlyService> {
-    let domain = format!("{}.edgecompute.app", slug);
-
+  pub fn create_service(&self, slug: &str) -> Result<FastlyService> {
     // Create a service
     let servreq = FastlyServiceRequest {
       service_type: "wasm".to_string(),
-      name: format!("{} via Quick Deploy", slug).to_string(),
+      name: format!("{} via Quick Deploy", slug),
     };
 
     let req = self
@@ -69,110 +70,327 @@ impl FastlyClient {
       .with_body_json(&servreq)?;
     let mut resp = req.send(API_BACKEND)?;
 
-    let mut service = match resp.get_status() {
+    let service = match resp.get_status() {
       StatusCode::OK => resp.take_body_json::<FastlyService>()?,
       _ => bail!("Error while creating service: {}", resp.take_body_str())
     };
     println!("Created service {}", service.id);
 
-    // Create a domain
+    Ok(service)
+  }
+
+  pub fn delete_service(&self, service_id: &str) -> Result<()> {
+    let req = self.fastly_request(Request::new(
+      Method::DELETE,
+      format!("https://api.fastly.com/service/{}", service_id),
+    ))?;
+    let mut resp = req.send(API_BACKEND)?;
+    match resp.get_status() {
+      StatusCode::OK => {
+        println!("Deleted service {}", service_id);
+        Ok(())
+      }
+      _ => bail!("Error while deleting service {}: {}", service_id, resp.take_body_str())
+    }
+  }
+
+  /// Lists the user's Compute@Edge services.
+  pub fn list_services(&self) -> Result<Vec<FastlyServiceSummary>> {
+    let req = self.fastly_request(Request::new(Method::GET, "https://api.fastly.com/service"))?;
+    let mut resp = req.send(API_BACKEND)?;
+    let services: Vec<FastlyServiceSummary> = match resp.get_status() {
+      StatusCode::OK => resp.take_body_json()?,
+      _ => bail!("Error while listing services: {}", resp.take_body_str())
+    };
+
+    Ok(services.into_iter().filter(|service| service.service_type == "wasm").collect())
+  }
+
+  pub fn get_service(&self, service_id: &str) -> Result<FastlyServiceDetails> {
+    let req = self.fastly_request(Request::new(
+      Method::GET,
+      format!("https://api.fastly.com/service/{}", service_id),
+    ))?;
+    let mut resp = req.send(API_BACKEND)?;
+    match resp.get_status() {
+      StatusCode::OK => Ok(resp.take_body_json()?),
+      StatusCode::NOT_FOUND => bail!("No Fastly service was found with ID {}", service_id),
+      _ => bail!("Error while fetching service {}: {}", service_id, resp.take_body_str())
+    }
+  }
+
+  /// Clones the active (or otherwise latest) version of an existing service into a new draft
+  /// version, returning its number.
+  pub fn clone_service_version(&self, service_id: &str) -> Result<i32> {
+    let service = self.get_service(service_id)?;
+    if service.service_type != "wasm" {
+      bail!("Service {} is not a Compute@Edge service", service_id);
+    }
+
+    let base_version = match service.active_version() {
+      Some(number) => number,
+      None => match service.latest_version() {
+        Some(version) => version.number,
+        None => bail!("Service {} has no versions to clone", service_id),
+      },
+    };
+
+    let req = self.fastly_request(Request::new(
+      Method::PUT,
+      format!(
+        "https://api.fastly.com/service/{}/version/{}/clone",
+        service_id, base_version
+      ),
+    ))?;
+    let mut resp = req.send(API_BACKEND)?;
+    let version: FastlyVersion = match resp.get_status() {
+      StatusCode::OK => resp.take_body_json()?,
+      _ => bail!("Error while cloning version {} of service {}: {}", base_version, service_id, resp.take_body_str())
+    };
+    println!("Cloned version {} of service {} to version {}", base_version, service_id, version.number);
+
+    Ok(version.number)
+  }
+
+  /// Lists the resources of one kind (e.g. "backend") on a service version.
+  fn list_resources<T: DeserializeOwned>(&self, service_id: &str, version: i32, kind: &str) -> Result<Vec<T>> {
+    let req = self.fastly_request(Request::new(
+      Method::GET,
+      format!(
+        "https://api.fastly.com/service/{}/version/{}/{}",
+        service_id, version, kind
+      ),
+    ))?;
+    let mut resp = req.send(API_BACKEND)?;
+    match resp.get_status() {
+      StatusCode::OK => Ok(resp.take_body_json()?),
+      _ => bail!("Error while listing {} resources: {}", kind, resp.take_body_str())
+    }
+  }
+
+  pub fn list_domains(&self, service_id: &str, version: i32) -> Result<Vec<FastlyDomain>> {
+    self.list_resources(service_id, version, "domain")
+  }
+
+  pub fn create_domain(&self, service_id: &str, version: i32, name: &str) -> Result<FastlyDomain> {
+    // Reuse the domain if a previous attempt already created it
+    if let Some(domain) = self
+      .list_domains(service_id, version)?
+      .into_iter()
+      .find(|domain| domain.name == name)
+    {
+      println!("Domain {} already exists", domain.name);
+      return Ok(domain);
+    }
+
     let req = self
       .fastly_request(Request::new(
         Method::POST,
         format!(
-          "https://api.fastly.com/service/{}/version/1/domain",
-          service.id
+          "https://api.fastly.com/service/{}/version/{}/domain",
+          service_id, version
         ),
       ))?
       .with_pass(true)
-      .with_body_json(&FastlyDomain { name: domain })?;
+      .with_body_json(&FastlyDomain { name: name.to_string() })?;
     let mut resp = req.send(API_BACKEND)?;
 
-    let domain = match resp.take_body_json::<FastlyDomain>() {
-      Ok(domain) => domain,
-      Err(err) => bail!("Error while creating domain: {}", err),
+    let domain = match resp.get_status() {
+      StatusCode::OK => resp.take_body_json::<FastlyDomain>()?,
+      _ => bail!("Error while creating domain {}: {}", name, resp.take_body_str()),
     };
     println!("Created domain {}", domain.name);
 
-    service.domain = Some(domain.name);
+    Ok(domain)
+  }
 
-    // Create backends
-    if deploy.spec.backends.len() == 0 {
-      deploy.spec.backends.push(BackendSpec {
-        name: "127.0.0.1".to_string(),
-        address: "127.0.0.1".to_string(),
-        port: None,
-        prompt: None,
-      });
-    }
+  /// Creates the backends on a service version, updating any that already exist with the same name.
+  pub fn create_backends(&self, service_id: &str, version: i32, backends: &[BackendSpec]) -> Result<()> {
+    let default_backends = [BackendSpec {
+      name: "127.0.0.1".to_string(),
+      address: "127.0.0.1".to_string(),
+      ..BackendSpec::default()
+    }];
+
+    let backends = if backends.is_empty() {
+      &default_backends[..]
+    } else {
+      backends
+    };
+
+    let existing = self.list_resources::<FastlyBackend>(service_id, version, "backend")?;
+
+    for backend in backends {
+      // Backends refer to their health check by name, so it must exist first
+      if let Some(healthcheck) = &backend.healthcheck {
+        self.create_healthcheck(service_id, version, backend, healthcheck)?;
+      }
+
+      let exists = existing.iter().any(|existing| existing.name == backend.name);
 
-    for backend in deploy.spec.backends {
       let req = match self
-        .fastly_request(Request::new(
-          Method::POST,
-          format!(
-            "https://api.fastly.com/service/{}/version/1/backend",
-            service.id
-          ),
-        ))?
+        .fastly_request(if exists {
+          Request::new(
+            Method::PUT,
+            format!(
+              "https://api.fastly.com/service/{}/version/{}/backend/{}",
+              service_id, version, backend.name
+            ),
+          )
+        } else {
+          Request::new(
+            Method::POST,
+            format!(
+              "https://api.fastly.com/service/{}/version/{}/backend",
+              service_id, version
+            ),
+          )
+        })?
         .with_pass(true)
-        .with_body_json(&FastlyBackend {
-          name: backend.name.to_owned(),
-          address: backend.address,
-          port: backend.port.unwrap_or(80),
-        }) {
+        .with_body_json(&FastlyBackend::from_spec(backend)) {
         Ok(req) => req,
         Err(err) => bail!("Error while creating backend {}: {}", backend.name, err),
       };
-      req.send(API_BACKEND)?;
-      println!("Created backend {}", backend.name);
+      let mut resp = req.send(API_BACKEND)?;
+      match resp.get_status() {
+        StatusCode::OK if exists => println!("Updated backend {}", backend.name),
+        StatusCode::OK => println!("Created backend {}", backend.name),
+        _ => bail!("Error while creating backend {}: {}", backend.name, resp.take_body_str()),
+      }
     }
 
-    for dict in deploy.spec.dictionaries {
-      // Create dictionary
-      let req = match self
-        .fastly_request(Request::new(
+    Ok(())
+  }
+
+  /// Creates the health check for a backend, updating it if it already exists.
+  fn create_healthcheck(
+    &self,
+    service_id: &str,
+    version: i32,
+    backend: &BackendSpec,
+    spec: &HealthcheckSpec,
+  ) -> Result<()> {
+    let exists = self
+      .list_resources::<FastlyHealthcheck>(service_id, version, "healthcheck")?
+      .iter()
+      .any(|existing| existing.name == backend.name);
+
+    let healthcheck = FastlyHealthcheck::from_spec(backend, spec);
+
+    let req = self
+      .fastly_request(if exists {
+        Request::new(
+          Method::PUT,
+          format!(
+            "https://api.fastly.com/service/{}/version/{}/healthcheck/{}",
+            service_id, version, backend.name
+          ),
+        )
+      } else {
+        Request::new(
           Method::POST,
           format!(
-            "https://api.fastly.com/service/{}/version/1/dictionary",
-            service.id
+            "https://api.fastly.com/service/{}/version/{}/healthcheck",
+            service_id, version
           ),
-        ))?
-        .with_pass(true)
-        .with_body_json(&FastlyDictionary {
-          id: None,
-          name: dict.name.to_owned(),
-        }) {
-        Ok(req) => req,
-        Err(err) => bail!("Error while creating dictionary {}: {}", dict.name, err),
-      };
-      let mut resp = req.send(API_BACKEND)?;
-      let created_dict: FastlyDictionary = resp.take_body_json()?;
-      println!("Created dictionary {}", dict.name);
+        )
+      })?
+      .with_body_json(&healthcheck)?;
+    let mut resp = req.send(API_BACKEND)?;
+    match resp.get_status() {
+      StatusCode::OK if exists => println!("Updated health check {}", backend.name),
+      StatusCode::OK => println!("Created health check {}", backend.name),
+      _ => bail!("Error while creating health check {}: {}", backend.name, resp.take_body_str()),
+    }
 
+    Ok(())
+  }
+
+  /// Creates a logging endpoint on a service version, updating it if it already exists.
+  pub fn create_log_endpoint(
+    &self,
+    service_id: &str,
+    version: i32,
+    provider: LogProvider,
+    name: &str,
+    fields: Vec<(String, String)>,
+  ) -> Result<()> {
+    let kind = format!("logging/{}", provider.path());
+    let exists = self
+      .list_resources::<FastlyLogEndpoint>(service_id, version, &kind)?
+      .iter()
+      .any(|existing| existing.name == name);
+
+    let endpoint = log_endpoint_body(provider, name, fields)?;
+
+    let req = self
+      .fastly_request(if exists {
+        Request::new(
+          Method::PUT,
+          format!(
+            "https://api.fastly.com/service/{}/version/{}/{}/{}",
+            service_id, version, kind, name
+          ),
+        )
+      } else {
+        Request::new(
+          Method::POST,
+          format!(
+            "https://api.fastly.com/service/{}/version/{}/{}",
+            service_id, version, kind
+          ),
+        )
+      })?
+      .with_body_json(&endpoint)?;
+    let mut resp = req.send(API_BACKEND)?;
+    match resp.get_status() {
+      StatusCode::OK if exists => println!("Updated {} log endpoint {}", provider.path(), name),
+      StatusCode::OK => println!("Created {} log endpoint {}", provider.path(), name),
+      _ => bail!("Error while creating log endpoint {}: {}", name, resp.take_body_str()),
+    }
+
+    Ok(())
+  }
+
+  pub fn create_dictionaries(&self, service_id: &str, version: i32, deploy: &DeployConfig) -> Result<()> {
+    let existing = self.list_resources::<FastlyDictionary>(service_id, version, "dictionary")?;
+
+    for dict in &deploy.spec.dictionaries {
       let mut entries: Vec<FastlyDictionaryItemAction> = vec![];
-      for entry in dict.items {
+      // Secrets are written to the dictionary's secret store instead
+      for entry in dict.items.iter().filter(|entry| !dict.is_secret(entry)) {
         entries.push(FastlyDictionaryItemAction {
-          op: "create".to_string(),
+          op: "upsert".to_string(),
           item_key: entry.key.to_owned(),
-          item_value: match deploy.params.get(&format!("dict.{}.{}", dict.name, entry.key)) {
-            Some(value) => value.to_string(),
-            None => match entry.value {
-              Some(default) => default,
-              None => bail!("No value provided for dict key {}", entry.key)
-            }
-          },
+          item_value: deploy.dictionary_item_value(dict, entry)?,
         });
       }
 
+      // Nothing is left for the dictionary if every item is a secret
+      if entries.is_empty() && dict.secret_store.is_some() {
+        continue;
+      }
+
+      // Reuse the dictionary if a previous attempt already created it
+      let created_dict = match existing.iter().find(|existing| existing.name == dict.name) {
+        Some(existing) => {
+          println!("Dictionary {} already exists", dict.name);
+          FastlyDictionary {
+            id: existing.id.to_owned(),
+            name: existing.name.to_owned(),
+          }
+        }
+        None => self.create_dictionary(service_id, version, &dict.name)?,
+      };
+
       let entry_count = entries.len();
 
-      match self
+      let mut resp = match self
         .fastly_request(Request::new(
           Method::PATCH,
           format!(
             "https://api.fastly.com/service/{}/dictionary/{}/items",
-            service.id,
+            service_id,
             created_dict.id.unwrap()
           ),
         ))?
@@ -180,31 +398,221 @@ impl FastlyClient {
         .with_body_json(&FastlyDictionaryUpdateRequest { items: entries })?
         .send(API_BACKEND)
       {
-        Ok(_) => {
-          println!("Populated dictionary {} with {} items", dict.name, entry_count);
-        },
+        Ok(resp) => resp,
         Err(err) => bail!(
           "Error while adding items to dictionary {}: {:?}",
           dict.name,
           err
         ),
       };
+
+      match resp.get_status() {
+        StatusCode::OK => {
+          println!("Populated dictionary {} with {} items", dict.name, entry_count);
+        },
+        _ => bail!(
+          "Error while adding items to dictionary {}: {}",
+          dict.name,
+          resp.take_body_str()
+        ),
+      }
     }
 
-    Ok(service)
+    Ok(())
+  }
+
+  fn create_dictionary(&self, service_id: &str, version: i32, name: &str) -> Result<FastlyDictionary> {
+    let req = match self
+      .fastly_request(Request::new(
+        Method::POST,
+        format!(
+          "https://api.fastly.com/service/{}/version/{}/dictionary",
+          service_id, version
+        ),
+      ))?
+      .with_pass(true)
+      .with_body_json(&FastlyDictionary {
+        id: None,
+        name: name.to_owned(),
+      }) {
+      Ok(req) => req,
+      Err(err) => bail!("Error while creating dictionary {}: {}", name, err),
+    };
+    let mut resp = req.send(API_BACKEND)?;
+    let created_dict: FastlyDictionary = match resp.get_status() {
+      StatusCode::OK => resp.take_body_json()?,
+      _ => bail!("Error while creating dictionary {}: {}", name, resp.take_body_str()),
+    };
+    println!("Created dictionary {}", name);
+
+    Ok(created_dict)
   }
 
-  pub fn check_service_deployment(&self, service_id: &str) -> Result<bool> {
+  /// Finds a store of the given kind on the user's account by name.
+  pub fn find_store(&self, kind: StoreKind, name: &str) -> Result<Option<FastlyStore>> {
     let req = self.fastly_request(Request::new(
       Method::GET,
-      format!("https://api.fastly.com/service/{}/version/1", service_id),
+      format!(
+        "https://api.fastly.com/resources/stores/{}?name={}",
+        kind.path(),
+        utf8_percent_encode(name, NON_ALPHANUMERIC)
+      ),
+    ))?;
+    let mut resp = req.send(API_BACKEND)?;
+    let stores = match resp.get_status() {
+      StatusCode::OK => match resp.take_body_json::<FastlyStoreList>()? {
+        FastlyStoreList::Paginated { data } => data,
+        FastlyStoreList::Plain(stores) => stores,
+      },
+      _ => bail!("Error while listing {} stores: {}", kind.path(), resp.take_body_str()),
+    };
+
+    Ok(stores.into_iter().find(|store| store.name == name))
+  }
+
+  pub fn create_store(&self, kind: StoreKind, name: &str) -> Result<FastlyStore> {
+    let req = self
+      .fastly_request(Request::new(
+        Method::POST,
+        format!("https://api.fastly.com/resources/stores/{}", kind.path()),
+      ))?
+      .with_body_json(&FastlyStore {
+        id: None,
+        name: name.to_owned(),
+      })?;
+    let mut resp = req.send(API_BACKEND)?;
+    let store: FastlyStore = match resp.get_status() {
+      StatusCode::OK | StatusCode::CREATED => resp.take_body_json()?,
+      _ => bail!("Error while creating {} store {}: {}", kind.path(), name, resp.take_body_str()),
+    };
+    println!("Created {} store {}", kind.path(), name);
+
+    Ok(store)
+  }
+
+  pub fn delete_store(&self, kind: StoreKind, store_id: &str) -> Result<()> {
+    let req = self.fastly_request(Request::new(
+      Method::DELETE,
+      format!("https://api.fastly.com/resources/stores/{}/{}", kind.path(), store_id),
     ))?;
     let mut resp = req.send(API_BACKEND)?;
     match resp.get_status() {
-      StatusCode::OK => Ok(resp.take_body_json::<FastlyServiceStatusResponse>()?.active),
-      _ => bail!("Unable to authenticate with Fastly")
+      StatusCode::OK | StatusCode::NO_CONTENT | StatusCode::NOT_FOUND => {
+        println!("Deleted {} store {}", kind.path(), store_id);
+        Ok(())
+      }
+      _ => bail!("Error while deleting {} store {}: {}", kind.path(), store_id, resp.take_body_str())
+    }
+  }
+
+  /// Creates or updates items in a config store.
+  pub fn upsert_config_items(&self, store_id: &str, items: Vec<(String, String)>) -> Result<()> {
+    let items: Vec<FastlyDictionaryItemAction> = items
+      .into_iter()
+      .map(|(key, value)| FastlyDictionaryItemAction {
+        op: "upsert".to_string(),
+        item_key: key,
+        item_value: value,
+      })
+      .collect();
+    let item_count = items.len();
+
+    let req = self
+      .fastly_request(Request::new(
+        Method::PATCH,
+        format!("https://api.fastly.com/resources/stores/config/{}/items", store_id),
+      ))?
+      .with_body_json(&FastlyDictionaryUpdateRequest { items })?;
+    let mut resp = req.send(API_BACKEND)?;
+    match resp.get_status() {
+      StatusCode::OK => {
+        println!("Populated config store {} with {} items", store_id, item_count);
+        Ok(())
+      }
+      _ => bail!("Error while adding items to config store {}: {}", store_id, resp.take_body_str())
+    }
+  }
+
+  /// Creates or replaces a secret in a secret store.
+  pub fn put_secret(&self, store_id: &str, name: &str, value: &str) -> Result<()> {
+    let req = self
+      .fastly_request(Request::new(
+        Method::PUT,
+        format!("https://api.fastly.com/resources/stores/secret/{}/secrets", store_id),
+      ))?
+      .with_body_json(&FastlySecret {
+        name: name.to_owned(),
+        secret: base64::encode(value),
+      })?;
+    let mut resp = req.send(API_BACKEND)?;
+    match resp.get_status() {
+      StatusCode::OK | StatusCode::CREATED => {
+        println!("Stored secret {}", name);
+        Ok(())
+      }
+      _ => bail!("Error while storing secret {}: {}", name, resp.take_body_str())
     }
   }
+
+  /// Links an account-level resource, such as a secret store, to a service version under `name`.
+  pub fn link_resource(&self, service_id: &str, version: i32, resource_id: &str, name: &str) -> Result<()> {
+    // Keep the link if a previous attempt already created it
+    if self
+      .list_resources::<FastlyResourceLink>(service_id, version, "resource")?
+      .iter()
+      .any(|link| link.name == name)
+    {
+      println!("Resource link {} already exists", name);
+      return Ok(());
+    }
+
+    let req = self
+      .fastly_request(Request::new(
+        Method::POST,
+        format!(
+          "https://api.fastly.com/service/{}/version/{}/resource",
+          service_id, version
+        ),
+      ))?
+      .with_body_json(&FastlyResourceLink {
+        resource_id: resource_id.to_owned(),
+        name: name.to_owned(),
+      })?;
+    let mut resp = req.send(API_BACKEND)?;
+    match resp.get_status() {
+      StatusCode::OK => {
+        println!("Linked resource {} as {}", resource_id, name);
+        Ok(())
+      }
+      _ => bail!("Error while linking resource {}: {}", name, resp.take_body_str())
+    }
+  }
+
+  pub fn put_kv_entry(&self, store_id: &str, key: &str, value: Vec<u8>) -> Result<()> {
+    let req = self
+      .fastly_request(Request::new(
+        Method::PUT,
+        format!(
+          "https://api.fastly.com/resources/stores/kv/{}/keys/{}",
+          store_id,
+          utf8_percent_encode(key, NON_ALPHANUMERIC)
+        ),
+      ))?
+      .with_body(value);
+    let mut resp = req.send(API_BACKEND)?;
+    match resp.get_status() {
+      StatusCode::OK | StatusCode::CREATED | StatusCode::NO_CONTENT => Ok(()),
+      _ => bail!("Error while writing KV entry {}: {}", key, resp.take_body_str())
+    }
+  }
+
+  /// Returns the number of the service's active version, if it is at least `min_version`.
+  pub fn check_service_deployment(&self, service_id: &str, min_version: i32) -> Result<Option<i32>> {
+    let service = self.get_service(service_id)?;
+    Ok(service
+      .active_version()
+      .filter(|version| *version >= min_version))
+  }
 }
 
 #[derive(Serialize)]
@@ -217,12 +625,42 @@ pub struct FastlyServiceRequest {
 #[derive(Deserialize)]
 pub struct FastlyService {
   pub id: String,
-  pub domain: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct FastlyServiceSummary {
+  pub id: String,
+  pub name: String,
+  #[serde(rename = "type")]
+  pub service_type: String,
 }
 
 #[derive(Deserialize)]
-pub struct FastlyServiceStatusResponse {
-  pub active: bool
+pub struct FastlyServiceDetails {
+  #[serde(rename = "type")]
+  pub service_type: String,
+  pub versions: Vec<FastlyVersion>,
+}
+
+impl FastlyServiceDetails {
+  pub fn active_version(&self) -> Option<i32> {
+    self
+      .versions
+      .iter()
+      .find(|version| version.active)
+      .map(|version| version.number)
+  }
+
+  pub fn latest_version(&self) -> Option<&FastlyVersion> {
+    self.versions.iter().max_by_key(|version| version.number)
+  }
+}
+
+#[derive(Deserialize)]
+pub struct FastlyVersion {
+  pub number: i32,
+  #[serde(default)]
+  pub active: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -248,11 +686,174 @@ pub struct FastlyDictionaryItemAction {
   pub item_value: String,
 }
 
+/// The kinds of account-level store that can be linked to a service.
+#[derive(Clone, Copy)]
+pub enum StoreKind {
+  Config,
+  Kv,
+  Secret,
+}
+
+impl StoreKind {
+  pub fn path(&self) -> &'static str {
+    match self {
+      StoreKind::Config => "config",
+      StoreKind::Kv => "kv",
+      StoreKind::Secret => "secret",
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FastlyStore {
+  pub id: Option<String>,
+  pub name: String,
+}
+
+/// Config stores are listed as a plain array, while KV and secret stores are paginated.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum FastlyStoreList {
+  Paginated { data: Vec<FastlyStore> },
+  Plain(Vec<FastlyStore>),
+}
+
 #[derive(Serialize)]
+pub struct FastlySecret {
+  pub name: String,
+  /// Base64-encoded value
+  pub secret: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FastlyResourceLink {
+  pub resource_id: String,
+  pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct FastlyLogEndpoint {
+  pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FastlyHealthcheck {
+  pub name: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub path: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub host: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub method: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub expected_response: Option<i32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub check_interval: Option<i32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub threshold: Option<i32>,
+}
+
+impl FastlyHealthcheck {
+  /// Builds the health check of a backend, named after it. Checks send the backend's host unless
+  /// the spec names one.
+  pub fn from_spec(backend: &BackendSpec, spec: &HealthcheckSpec) -> FastlyHealthcheck {
+    FastlyHealthcheck {
+      name: backend.name.to_owned(),
+      path: Some(spec.path.to_owned()),
+      host: spec
+        .host
+        .as_ref()
+        .or(backend.override_host.as_ref())
+        .or(Some(&backend.address))
+        .cloned(),
+      method: spec.method.to_owned(),
+      expected_response: spec.expected_response,
+      check_interval: spec.check_interval,
+      threshold: spec.threshold,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct FastlyBackend {
   pub name: String,
   pub address: String,
   pub port: i32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub use_ssl: Option<bool>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub ssl_cert_hostname: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub ssl_sni_hostname: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub override_host: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub connect_timeout: Option<i32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub first_byte_timeout: Option<i32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub between_bytes_timeout: Option<i32>,
+  /// Name of the health check polling this backend
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub healthcheck: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub shield: Option<String>,
+}
+
+impl FastlyBackend {
+  /// Builds a backend from the setup spec, enabling TLS for port 443 unless told otherwise.
+  /// TLS backends verify their certificate against the host they are addressed by.
+  pub fn from_spec(spec: &BackendSpec) -> FastlyBackend {
+    let port = match (spec.port, spec.use_ssl) {
+      (Some(port), _) => port,
+      (None, Some(true)) => 443,
+      (None, _) => 80,
+    };
+    let use_ssl = spec.use_ssl.unwrap_or(port == 443);
+
+    let tls_hostname = |hostname: &Option<String>| {
+      if !use_ssl {
+        return hostname.to_owned();
+      }
+      hostname
+        .as_ref()
+        .or(spec.override_host.as_ref())
+        .or(Some(&spec.address))
+        .cloned()
+    };
+
+    FastlyBackend {
+      name: spec.name.to_owned(),
+      address: spec.address.to_owned(),
+      port,
+      use_ssl: Some(use_ssl),
+      ssl_cert_hostname: tls_hostname(&spec.ssl_cert_hostname),
+      ssl_sni_hostname: tls_hostname(&spec.ssl_sni_hostname),
+      override_host: spec.override_host.to_owned(),
+      connect_timeout: spec.connect_timeout,
+      first_byte_timeout: spec.first_byte_timeout,
+      between_bytes_timeout: spec.between_bytes_timeout,
+      healthcheck: spec.healthcheck.as_ref().map(|_| spec.name.to_owned()),
+      shield: spec.shield.to_owned(),
+    }
+  }
+}
+
+/// Builds the API settings of a logging endpoint, checking that the provider's required fields
+/// are set. A field entered by the user may still be blank after the manifest check has passed.
+fn log_endpoint_body(
+  provider: LogProvider,
+  name: &str,
+  fields: Vec<(String, String)>,
+) -> Result<BTreeMap<String, String>> {
+  let mut endpoint: BTreeMap<String, String> = fields.into_iter().collect();
+  for required in provider.required_fields() {
+    if endpoint.get(*required).map_or(true, |value| value.is_empty()) {
+      bail!("Log endpoint {} is missing the {} field", name, required);
+    }
+  }
+  endpoint.insert("name".to_string(), name.to_owned());
+  Ok(endpoint)
 }
 
 #[derive(Deserialize, Serialize)]
@@ -265,3 +866,160 @@ pub struct FastlyUser {
 pub struct AuthParams {
   pub token: String,
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn backend(port: Option<i32>, use_ssl: Option<bool>) -> BackendSpec {
+    BackendSpec {
+      name: "origin".to_string(),
+      address: "origin.example.com".to_string(),
+      port,
+      use_ssl,
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn infers_port_and_tls() {
+    let plain = FastlyBackend::from_spec(&backend(None, None));
+    assert_eq!(plain.port, 80);
+    assert_eq!(plain.use_ssl, Some(false));
+
+    let tls = FastlyBackend::from_spec(&backend(None, Some(true)));
+    assert_eq!(tls.port, 443);
+    assert_eq!(tls.use_ssl, Some(true));
+
+    let https_port = FastlyBackend::from_spec(&backend(Some(443), None));
+    assert_eq!(https_port.use_ssl, Some(true));
+
+    let other_port = FastlyBackend::from_spec(&backend(Some(8080), None));
+    assert_eq!(other_port.port, 8080);
+    assert_eq!(other_port.use_ssl, Some(false));
+
+    // An explicit setting wins over the port
+    let plain_443 = FastlyBackend::from_spec(&backend(Some(443), Some(false)));
+    assert_eq!(plain_443.port, 443);
+    assert_eq!(plain_443.use_ssl, Some(false));
+
+    let tls_8443 = FastlyBackend::from_spec(&backend(Some(8443), Some(true)));
+    assert_eq!(tls_8443.port, 8443);
+    assert_eq!(tls_8443.use_ssl, Some(true));
+  }
+
+  #[test]
+  fn verifies_tls_backends_against_their_host() {
+    let tls = FastlyBackend::from_spec(&backend(Some(443), None));
+    assert_eq!(tls.ssl_cert_hostname.as_deref(), Some("origin.example.com"));
+    assert_eq!(tls.ssl_sni_hostname.as_deref(), Some("origin.example.com"));
+
+    let overridden = FastlyBackend::from_spec(&BackendSpec {
+      override_host: Some("www.example.com".to_string()),
+      ..backend(Some(443), None)
+    });
+    assert_eq!(overridden.ssl_cert_hostname.as_deref(), Some("www.example.com"));
+    assert_eq!(overridden.ssl_sni_hostname.as_deref(), Some("www.example.com"));
+
+    let explicit = FastlyBackend::from_spec(&BackendSpec {
+      override_host: Some("www.example.com".to_string()),
+      ssl_cert_hostname: Some("cert.example.com".to_string()),
+      ssl_sni_hostname: Some("sni.example.com".to_string()),
+      ..backend(Some(443), None)
+    });
+    assert_eq!(explicit.ssl_cert_hostname.as_deref(), Some("cert.example.com"));
+    assert_eq!(explicit.ssl_sni_hostname.as_deref(), Some("sni.example.com"));
+  }
+
+  #[test]
+  fn leaves_plain_backends_without_tls_hostnames() {
+    let plain = FastlyBackend::from_spec(&BackendSpec {
+      override_host: Some("www.example.com".to_string()),
+      ..backend(None, None)
+    });
+    assert_eq!(plain.ssl_cert_hostname, None);
+    assert_eq!(plain.ssl_sni_hostname, None);
+    assert_eq!(plain.override_host.as_deref(), Some("www.example.com"));
+  }
+
+  fn fields(fields: &[(&str, &str)]) -> Vec<(String, String)> {
+    fields
+      .iter()
+      .map(|(key, value)| (key.to_string(), value.to_string()))
+      .collect()
+  }
+
+  #[test]
+  fn requires_log_endpoint_fields() {
+    let providers = [
+      (LogProvider::Https, "url", "https://logs.example.com"),
+      (LogProvider::Syslog, "address", "syslog.example.com"),
+      (LogProvider::S3, "bucket_name", "logs"),
+    ];
+    for (provider, required, value) in providers {
+      let set = fields(&[(required, value), ("format", "%h")]);
+      let body = log_endpoint_body(provider, "logs", set).unwrap();
+      assert_eq!(body.get(required).map(String::as_str), Some(value));
+      assert_eq!(body.get("format").map(String::as_str), Some("%h"));
+      assert_eq!(body.get("name").map(String::as_str), Some("logs"));
+
+      let message = format!("Log endpoint logs is missing the {} field", required);
+      let missing = log_endpoint_body(provider, "logs", fields(&[("format", "%h")]));
+      assert_eq!(missing.unwrap_err().to_string(), message);
+      let blank = log_endpoint_body(provider, "logs", fields(&[(required, "")]));
+      assert_eq!(blank.unwrap_err().to_string(), message);
+    }
+  }
+
+  fn healthcheck(extra: &str) -> HealthcheckSpec {
+    toml::from_str(&format!("path = \"/health\"\n{}", extra)).unwrap()
+  }
+
+  #[test]
+  fn defaults_healthchecks_to_the_backend_host() {
+    let check = FastlyHealthcheck::from_spec(&backend(None, None), &healthcheck(""));
+    assert_eq!(check.name, "origin");
+    assert_eq!(check.path.as_deref(), Some("/health"));
+    assert_eq!(check.host.as_deref(), Some("origin.example.com"));
+    // Left to the API's defaults
+    assert_eq!(check.method, None);
+    assert_eq!(check.expected_response, None);
+    assert_eq!(check.check_interval, None);
+    assert_eq!(check.threshold, None);
+
+    let overridden = BackendSpec {
+      override_host: Some("www.example.com".to_string()),
+      ..backend(None, None)
+    };
+    let check = FastlyHealthcheck::from_spec(&overridden, &healthcheck(""));
+    assert_eq!(check.host.as_deref(), Some("www.example.com"));
+
+    let named = healthcheck("host = \"health.example.com\"");
+    let check = FastlyHealthcheck::from_spec(&overridden, &named);
+    assert_eq!(check.host.as_deref(), Some("health.example.com"));
+  }
+
+  #[test]
+  fn keeps_healthcheck_settings() {
+    let spec = healthcheck(
+      r#"
+      method = "GET"
+      expected_response = 204
+      check_interval = 15000
+      threshold = 3
+      "#,
+    );
+    let check = FastlyHealthcheck::from_spec(&backend(None, None), &spec);
+    assert_eq!(check.method.as_deref(), Some("GET"));
+    assert_eq!(check.expected_response, Some(204));
+    assert_eq!(check.check_interval, Some(15000));
+    assert_eq!(check.threshold, Some(3));
+
+    // The backend refers to its health check by name
+    let with_check = BackendSpec {
+      healthcheck: Some(spec),
+      ..backend(None, None)
+    };
+    assert_eq!(FastlyBackend::from_spec(&with_check).healthcheck.as_deref(), Some("origin"));
+  }
+}
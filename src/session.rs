@@ -0,0 +1,427 @@
+use crate::crypto::{random_token, Keyring};
+use crate::state::ApplicationState;
+use anyhow::{bail, Result};
+use fastly::kv_store::{KVStore, KVStoreError};
+use fastly::{http::header, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Carries the opaque session ID
+const SESSION_COOKIE: &str = "__Secure-Deploy-Session";
+
+/// How long a session may live in total, in seconds
+const SESSION_LIFETIME: u64 = 60 * 60 * 24 * 7;
+
+/// How long a session may go unused before it expires, in seconds
+const IDLE_TIMEOUT: u64 = 60 * 60 * 24;
+
+/// Persists session records outside of the browser.
+pub trait SessionStore {
+  fn load(&self, id: &str) -> Result<Option<Vec<u8>>>;
+  /// Saves a record, which the store may discard once `ttl` has passed
+  fn save(&self, id: &str, record: Vec<u8>, ttl: Duration) -> Result<()>;
+  fn delete(&self, id: &str) -> Result<()>;
+}
+
+/// Stores sessions in a Fastly KV store linked to this service.
+pub struct KvSessionStore {
+  store: KVStore,
+}
+
+impl KvSessionStore {
+  pub fn get_default() -> Result<KvSessionStore> {
+    KvSessionStore::open("sessions")
+  }
+
+  pub fn open(store_name: &str) -> Result<KvSessionStore> {
+    match KVStore::open(store_name)? {
+      Some(store) => Ok(KvSessionStore { store }),
+      None => bail!("No KV store named {} is linked to this service", store_name),
+    }
+  }
+
+  fn key(id: &str) -> String {
+    format!("session_{}", id)
+  }
+}
+
+impl SessionStore for KvSessionStore {
+  fn load(&self, id: &str) -> Result<Option<Vec<u8>>> {
+    match self.store.lookup(&KvSessionStore::key(id)) {
+      Ok(mut found) => Ok(Some(found.take_body_bytes())),
+      Err(KVStoreError::ItemNotFound) => Ok(None),
+      Err(err) => Err(err.into()),
+    }
+  }
+
+  fn save(&self, id: &str, record: Vec<u8>, ttl: Duration) -> Result<()> {
+    self
+      .store
+      .build_insert()
+      .time_to_live(ttl)
+      .execute(&KvSessionStore::key(id), record)?;
+    Ok(())
+  }
+
+  fn delete(&self, id: &str) -> Result<()> {
+    match self.store.delete(&KvSessionStore::key(id)) {
+      Ok(()) | Err(KVStoreError::ItemNotFound) => Ok(()),
+      Err(err) => Err(err.into()),
+    }
+  }
+}
+
+/// Keeps sessions in memory for the lifetime of the store, for use in tests. Clones share the
+/// same records.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct MemorySessionStore {
+  records: std::rc::Rc<std::cell::RefCell<HashMap<String, Vec<u8>>>>,
+}
+
+#[cfg(test)]
+impl MemorySessionStore {
+  pub fn new() -> MemorySessionStore {
+    MemorySessionStore {
+      records: Default::default(),
+    }
+  }
+}
+
+#[cfg(test)]
+impl SessionStore for MemorySessionStore {
+  fn load(&self, id: &str) -> Result<Option<Vec<u8>>> {
+    Ok(self.records.borrow().get(id).cloned())
+  }
+
+  fn save(&self, id: &str, record: Vec<u8>, _ttl: Duration) -> Result<()> {
+    self.records.borrow_mut().insert(id.to_string(), record);
+    Ok(())
+  }
+
+  fn delete(&self, id: &str) -> Result<()> {
+    self.records.borrow_mut().remove(id);
+    Ok(())
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SessionRecord {
+  created_at: u64,
+  last_seen_at: u64,
+  state: String,
+}
+
+/// The server-side session for the current request. The browser only holds the sealed session ID.
+pub struct Session {
+  id: String,
+  created_at: u64,
+  store: Box<dyn SessionStore>,
+  keys: Keyring,
+  /// The state of a session that expired when this request tried to resume it
+  expired: Option<ApplicationState>,
+}
+
+impl Session {
+  /// Resumes the session referenced by the request cookie, or starts a new one if it is
+  /// missing, expired or unreadable.
+  pub fn resume(
+    store: Box<dyn SessionStore>,
+    keys: Keyring,
+    cookies: &HashMap<&str, &str>,
+  ) -> Result<(Session, ApplicationState)> {
+    let id = cookies
+      .get(SESSION_COOKIE)
+      .and_then(|cookie| keys.open(SESSION_COOKIE, cookie))
+      .and_then(|id| String::from_utf8(id).ok());
+
+    let now = now();
+    let mut expired = None;
+
+    if let Some(id) = id {
+      // A store that cannot be read is treated like a missing session, so that pages still load
+      let record = match load_record(store.as_ref(), &keys, &id) {
+        Ok(record) => record,
+        Err(err) => {
+          println!("Unable to load session, starting a new one: {}", err);
+          return Ok((Session::start(store, keys, None), ApplicationState::default()));
+        }
+      };
+
+      match record {
+        Some(record) if now.saturating_sub(record.created_at) > SESSION_LIFETIME => {
+          println!("Session expired after reaching its maximum lifetime");
+          expired = ApplicationState::decode(record.state.as_bytes()).ok();
+          discard(store.as_ref(), &id);
+        }
+        Some(record) if now.saturating_sub(record.last_seen_at) > IDLE_TIMEOUT => {
+          println!("Session expired after being idle");
+          expired = ApplicationState::decode(record.state.as_bytes()).ok();
+          discard(store.as_ref(), &id);
+        }
+        Some(record) => match ApplicationState::decode(record.state.as_bytes()) {
+          Ok(state) => {
+            let session = Session {
+              id,
+              created_at: record.created_at,
+              store,
+              keys,
+              expired: None,
+            };
+            return Ok((session, state));
+          }
+          Err(err) => {
+            println!("Resetting application state: {}", err);
+            discard(store.as_ref(), &id);
+          }
+        },
+        None => println!("Session not found, starting a new one"),
+      }
+    }
+
+    Ok((Session::start(store, keys, expired), ApplicationState::default()))
+  }
+
+  fn start(store: Box<dyn SessionStore>, keys: Keyring, expired: Option<ApplicationState>) -> Session {
+    Session {
+      id: random_token(),
+      created_at: now(),
+      store,
+      keys,
+      expired,
+    }
+  }
+
+  /// Takes the state of a session that expired when this request tried to resume it, so that
+  /// anything it left unfinished can be cleaned up.
+  pub fn take_expired(&mut self) -> Option<ApplicationState> {
+    self.expired.take()
+  }
+
+  /// Persists the state and attaches the session cookie to the response.
+  pub fn save(&self, resp: Response, state: &ApplicationState) -> Result<Response> {
+    self.persist(state)?;
+
+    let cookie = self.keys.seal(SESSION_COOKIE, self.id.as_bytes())?;
+    let max_age = SESSION_LIFETIME.saturating_sub(now().saturating_sub(self.created_at));
+
+    Ok(resp.with_header(
+      header::SET_COOKIE,
+      format!(
+        "{}={}; Max-Age={}; Secure; HttpOnly; SameSite=Lax; Path=/;",
+        SESSION_COOKIE, cookie, max_age
+      ),
+    ))
+  }
+
+  /// Persists the state without touching the cookie, e.g. to checkpoint a long-running request.
+  pub fn persist(&self, state: &ApplicationState) -> Result<()> {
+    let record = SessionRecord {
+      created_at: self.created_at,
+      last_seen_at: now(),
+      state: String::from_utf8(state.encode()?)?,
+    };
+    let sealed_record = self
+      .keys
+      .seal(&record_name(&self.id), &serde_json::to_vec(&record)?)?;
+
+    // Records outlive the session by an idle timeout, so that an abandoned deployment can still
+    // be found and cleaned up when the browser returns after the session has expired
+    let age = now().saturating_sub(self.created_at);
+    let ttl = SESSION_LIFETIME.saturating_sub(age) + IDLE_TIMEOUT;
+    self
+      .store
+      .save(&self.id, sealed_record.into_bytes(), Duration::from_secs(ttl))
+  }
+
+  /// Deletes the stored session and continues under a new ID, so that the old cookie can no
+  /// longer be used.
+  pub fn invalidate(&mut self) -> Result<()> {
+    self.store.delete(&self.id)?;
+    self.id = random_token();
+    self.created_at = now();
+    Ok(())
+  }
+}
+
+/// Deletes a session record that will not be used again. Failing to do so is harmless, as the
+/// record expires on its own.
+fn discard(store: &dyn SessionStore, id: &str) {
+  if let Err(err) = store.delete(id) {
+    println!("Unable to delete session record: {}", err);
+  }
+}
+
+/// Binds a sealed record to the session it belongs to
+fn record_name(id: &str) -> String {
+  format!("{}:{}", SESSION_COOKIE, id)
+}
+
+fn load_record(store: &dyn SessionStore, keys: &Keyring, id: &str) -> Result<Option<SessionRecord>> {
+  let sealed = match store.load(id)? {
+    Some(sealed) => sealed,
+    None => return Ok(None),
+  };
+
+  let record = String::from_utf8(sealed)
+    .ok()
+    .and_then(|sealed| keys.open(&record_name(id), &sealed))
+    .and_then(|record| serde_json::from_slice::<SessionRecord>(&record).ok());
+
+  if record.is_none() {
+    println!("Discarding session record that could not be opened");
+  }
+
+  Ok(record)
+}
+
+fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|duration| duration.as_secs())
+    .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn keys() -> Keyring {
+    Keyring::from_keys("1", vec![("1".to_string(), vec![7; 32])]).unwrap()
+  }
+
+  fn resume(store: &MemorySessionStore, cookie: Option<&str>) -> (Session, ApplicationState) {
+    let mut cookies = HashMap::new();
+    if let Some(cookie) = cookie {
+      cookies.insert(SESSION_COOKIE, cookie);
+    }
+    Session::resume(Box::new(store.clone()), keys(), &cookies).unwrap()
+  }
+
+  fn cookie(session: &Session) -> String {
+    keys().seal(SESSION_COOKIE, session.id.as_bytes()).unwrap()
+  }
+
+  /// Saves a session record as if it had been written at the given times
+  fn write_record(store: &MemorySessionStore, id: &str, created_at: u64, last_seen_at: u64) {
+    let mut state = ApplicationState::default();
+    state.deploy.src = Some("owner/repo".to_string());
+    let record = SessionRecord {
+      created_at,
+      last_seen_at,
+      state: String::from_utf8(state.encode().unwrap()).unwrap(),
+    };
+    let sealed = keys()
+      .seal(&record_name(id), &serde_json::to_vec(&record).unwrap())
+      .unwrap();
+    store
+      .save(id, sealed.into_bytes(), Duration::from_secs(SESSION_LIFETIME))
+      .unwrap();
+  }
+
+  struct FailingStore;
+
+  impl SessionStore for FailingStore {
+    fn load(&self, _id: &str) -> Result<Option<Vec<u8>>> {
+      bail!("store is unavailable")
+    }
+
+    fn save(&self, _id: &str, _record: Vec<u8>, _ttl: Duration) -> Result<()> {
+      bail!("store is unavailable")
+    }
+
+    fn delete(&self, _id: &str) -> Result<()> {
+      bail!("store is unavailable")
+    }
+  }
+
+  #[test]
+  fn resumes_saved_sessions() {
+    let store = MemorySessionStore::new();
+    let (session, mut state) = resume(&store, None);
+    assert_eq!(state.deploy.src, None);
+
+    state.deploy.src = Some("owner/repo".to_string());
+    session.persist(&state).unwrap();
+
+    let (resumed, state) = resume(&store, Some(&cookie(&session)));
+    assert_eq!(resumed.id, session.id);
+    assert_eq!(state.deploy.src.as_deref(), Some("owner/repo"));
+  }
+
+  #[test]
+  fn invalidated_sessions_cannot_be_resumed() {
+    let store = MemorySessionStore::new();
+    let (mut session, state) = resume(&store, None);
+    session.persist(&state).unwrap();
+    let old_cookie = cookie(&session);
+    let old_id = session.id.clone();
+
+    session.invalidate().unwrap();
+    assert_ne!(session.id, old_id);
+    assert!(store.load(&old_id).unwrap().is_none());
+
+    let (resumed, _) = resume(&store, Some(&old_cookie));
+    assert_ne!(resumed.id, old_id);
+  }
+
+  #[test]
+  fn expires_idle_sessions() {
+    let store = MemorySessionStore::new();
+    let now = now();
+    write_record(&store, "idle", now - 60, now - IDLE_TIMEOUT - 1);
+
+    let cookie = keys().seal(SESSION_COOKIE, b"idle").unwrap();
+    let (mut session, state) = resume(&store, Some(&cookie));
+
+    assert_ne!(session.id, "idle");
+    assert_eq!(state.deploy.src, None);
+    assert!(store.load("idle").unwrap().is_none());
+
+    // The expired state is handed over so that an unfinished deployment can be cleaned up
+    let expired = session.take_expired().unwrap();
+    assert_eq!(expired.deploy.src.as_deref(), Some("owner/repo"));
+  }
+
+  #[test]
+  fn expires_sessions_after_their_lifetime() {
+    let store = MemorySessionStore::new();
+    let now = now();
+    write_record(&store, "old", now - SESSION_LIFETIME - 1, now);
+
+    let cookie = keys().seal(SESSION_COOKIE, b"old").unwrap();
+    let (mut session, state) = resume(&store, Some(&cookie));
+
+    assert_ne!(session.id, "old");
+    assert_eq!(state.deploy.src, None);
+    assert!(store.load("old").unwrap().is_none());
+    assert!(session.take_expired().is_some());
+  }
+
+  #[test]
+  fn tampered_cookies_start_an_empty_session() {
+    let store = MemorySessionStore::new();
+    let (session, mut state) = resume(&store, None);
+    state.deploy.src = Some("owner/repo".to_string());
+    session.persist(&state).unwrap();
+
+    let mut tampered = cookie(&session);
+    tampered.push('x');
+    let (resumed, state) = resume(&store, Some(&tampered));
+
+    assert_ne!(resumed.id, session.id);
+    assert_eq!(state.deploy.src, None);
+  }
+
+  #[test]
+  fn store_errors_start_an_empty_session() {
+    let cookie = keys().seal(SESSION_COOKIE, b"id").unwrap();
+    let mut cookies = HashMap::new();
+    cookies.insert(SESSION_COOKIE, cookie.as_str());
+
+    let (session, state) = Session::resume(Box::new(FailingStore), keys(), &cookies).unwrap();
+    assert_ne!(session.id, "id");
+    assert_eq!(state.deploy.src, None);
+  }
+}
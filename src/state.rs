@@ -0,0 +1,226 @@
+use crate::github::{GitHubNWO, GitHubSource};
+use crate::provision::DeployStep;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The current version of the application state schema. Bump this and add a step to
+/// `migrate` whenever older payloads can no longer be deserialized as `ApplicationState`.
+/// Adding an optional field does not require a new version.
+pub const STATE_VERSION: u64 = 2;
+
+#[derive(Serialize, Deserialize)]
+pub struct ApplicationState {
+  pub version: u64,
+  pub login: LoginState,
+  pub deploy: DeploymentState,
+  /// Synchronizer token included in every form that changes state
+  pub csrf_token: Option<String>,
+}
+
+impl Default for ApplicationState {
+  fn default() -> ApplicationState {
+    ApplicationState {
+      version: STATE_VERSION,
+      login: LoginState::default(),
+      deploy: DeploymentState::default(),
+      csrf_token: None,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct LoginState {
+  pub fastly_token: Option<String>,
+  pub github_token: Option<String>,
+  /// Nonce sent as the `state` parameter of the GitHub authorization flow
+  pub oauth_state: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct DeploymentState {
+  /// The source repository, followed by `/tree/{ref}/{subdir}` when deploying from elsewhere
+  /// than the root of its default branch
+  pub src: Option<GitHubNWO>,
+  pub dest: Option<GitHubNWO>,
+  pub fastly_service_id: Option<String>,
+  /// Version of the service being provisioned
+  pub fastly_service_version: Option<i32>,
+  /// Whether the service existed before this deployment, in which case it is never deleted
+  #[serde(default)]
+  pub existing_service: bool,
+  pub fastly_domain: Option<String>,
+  /// Random name used for the service and its domain, kept across retries
+  pub slug: Option<String>,
+  /// Stores created for this deployment, which are not deleted along with the service
+  #[serde(default)]
+  pub config_store_ids: Vec<String>,
+  #[serde(default)]
+  pub kv_store_ids: Vec<String>,
+  #[serde(default)]
+  pub secret_store_ids: Vec<String>,
+  /// Values of `generated` setup items, keyed by form field, kept so that retries reuse them
+  #[serde(default)]
+  pub generated_values: BTreeMap<String, String>,
+  /// GitHub Actions secrets created by this deployment, including the Fastly API token
+  #[serde(default)]
+  pub repository_secrets: Vec<String>,
+  #[serde(default)]
+  pub completed_steps: Vec<DeployStep>,
+}
+
+impl DeploymentState {
+  pub fn source(&self) -> Option<GitHubSource> {
+    self.src.as_deref().and_then(GitHubSource::parse)
+  }
+
+  /// The repository being deployed to, without the source it was created from
+  pub fn dest_nwo(&self) -> Option<&str> {
+    match self.dest.as_ref() {
+      Some(dest) => dest.split('+').next_back(),
+      None => None,
+    }
+  }
+}
+
+#[derive(Debug)]
+pub enum StateError {
+  /// The payload is not valid UTF-8 or JSON
+  Corrupt(String),
+  /// The payload was written by a newer (or unknown) version of the application
+  UnsupportedVersion(u64),
+  /// The payload is valid JSON but does not match the schema for its version
+  InvalidSchema(u64, serde_json::Error),
+}
+
+impl fmt::Display for StateError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      StateError::Corrupt(reason) => write!(f, "state is corrupt: {}", reason),
+      StateError::UnsupportedVersion(version) => {
+        write!(f, "state has unsupported version {}", version)
+      }
+      StateError::InvalidSchema(version, err) => {
+        write!(f, "state does not match version {} schema: {}", version, err)
+      }
+    }
+  }
+}
+
+impl std::error::Error for StateError {}
+
+impl ApplicationState {
+  /// Decodes a serialized state, migrating payloads from older versions forward.
+  pub fn decode(payload: &[u8]) -> Result<ApplicationState, StateError> {
+    let json = match std::str::from_utf8(payload) {
+      Ok(json) => json,
+      Err(err) => return Err(StateError::Corrupt(err.to_string())),
+    };
+
+    let mut value: Value = match serde_json::from_str(json) {
+      Ok(value) => value,
+      Err(err) => return Err(StateError::Corrupt(err.to_string())),
+    };
+
+    if !value.is_object() {
+      return Err(StateError::Corrupt("expected a JSON object".to_string()));
+    }
+
+    // State written before versioning was introduced has no version field
+    let mut version = match value.get("version") {
+      Some(version) => match version.as_u64() {
+        Some(version) => version,
+        None => return Err(StateError::Corrupt("version is not a number".to_string())),
+      },
+      None => 1,
+    };
+
+    if version > STATE_VERSION || version == 0 {
+      return Err(StateError::UnsupportedVersion(version));
+    }
+
+    while version < STATE_VERSION {
+      migrate(&mut value, version);
+      version += 1;
+    }
+
+    serde_json::from_value(value).map_err(|err| StateError::InvalidSchema(version, err))
+  }
+
+  pub fn encode(&self) -> serde_json::Result<Vec<u8>> {
+    serde_json::to_vec(self)
+  }
+}
+
+/// Upgrades a state payload from `from_version` to the following version.
+fn migrate(value: &mut Value, from_version: u64) {
+  if from_version == 1 {
+    // Version 2 added the version field itself
+    value["version"] = Value::from(2);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn round_trips_current_state() {
+    let mut state = ApplicationState::default();
+    state.login.github_token = Some("token".to_string());
+    state.deploy.src = Some("owner/repo".to_string());
+
+    let decoded = ApplicationState::decode(&state.encode().unwrap()).unwrap();
+    assert_eq!(decoded.version, STATE_VERSION);
+    assert_eq!(decoded.login.github_token.as_deref(), Some("token"));
+    assert_eq!(decoded.deploy.src.as_deref(), Some("owner/repo"));
+  }
+
+  #[test]
+  fn migrates_unversioned_state() {
+    let payload = br#"{
+      "login": {"fastly_token": null, "github_token": "token"},
+      "deploy": {"src": "owner/repo", "dest": null, "fastly_service_id": null, "fastly_domain": null}
+    }"#;
+
+    let state = ApplicationState::decode(payload).unwrap();
+    assert_eq!(state.version, STATE_VERSION);
+    assert_eq!(state.login.github_token.as_deref(), Some("token"));
+    assert_eq!(state.login.oauth_state, None);
+    assert!(state.deploy.completed_steps.is_empty());
+  }
+
+  #[test]
+  fn rejects_unreadable_state() {
+    assert!(matches!(ApplicationState::decode(b"\xff"), Err(StateError::Corrupt(_))));
+    assert!(matches!(ApplicationState::decode(b"{"), Err(StateError::Corrupt(_))));
+    assert!(matches!(ApplicationState::decode(b"[]"), Err(StateError::Corrupt(_))));
+    assert!(matches!(
+      ApplicationState::decode(br#"{"version": "2"}"#),
+      Err(StateError::Corrupt(_))
+    ));
+  }
+
+  #[test]
+  fn rejects_unsupported_versions() {
+    let newer = format!(r#"{{"version": {}}}"#, STATE_VERSION + 1);
+    assert!(matches!(
+      ApplicationState::decode(newer.as_bytes()),
+      Err(StateError::UnsupportedVersion(_))
+    ));
+    assert!(matches!(
+      ApplicationState::decode(br#"{"version": 0}"#),
+      Err(StateError::UnsupportedVersion(0))
+    ));
+  }
+
+  #[test]
+  fn rejects_state_that_does_not_match_the_schema() {
+    let payload = format!(r#"{{"version": {}, "login": 42}}"#, STATE_VERSION);
+    assert!(matches!(
+      ApplicationState::decode(payload.as_bytes()),
+      Err(StateError::InvalidSchema(STATE_VERSION, _))
+    ));
+  }
+}
@@ -1,4 +1,6 @@
+use crate::form::{FormField, ForkForm, ServiceForm};
 use crate::github::{GitHubRepository, GitHubUser, GitHubNWO};
+use crate::provision::RollbackReport;
 use crate::scdn::FastlyUser;
 use crate::DeployConfigSpec;
 
@@ -12,17 +14,34 @@ pub struct TemplateRenderer<'a> {
 #[derive(Serialize)]
 pub struct DeployContext {
   pub src: GitHubRepository,
+  /// The route the page was served on, identifying the repository, ref and directory
+  pub src_path: String,
+  pub src_ref: Option<String>,
+  pub src_subdir: Option<String>,
   pub dest_nwo: Option<GitHubNWO>,
   pub github_user: Option<GitHubUser>,
   pub fastly_user: Option<FastlyUser>,
   pub can_fork: bool,
+  /// Where the new repository will be created, when it can be
+  pub fork: Option<ForkForm>,
   pub can_deploy: bool,
   pub config_spec: Option<DeployConfigSpec>,
+  pub fields: Vec<FormField>,
+  /// Whether a submission of the form was rejected
+  pub has_errors: bool,
+  pub resume_service_id: Option<String>,
+  pub service_form: ServiceForm,
+  pub csrf_token: String,
 }
 
 #[derive(Serialize)]
 pub struct ErrorContext {
   pub message: String,
+  pub csrf_token: String,
+  /// Resources created by an unfinished deployment, which cancelling it deletes
+  pub pending: Vec<String>,
+  pub rollback: Option<RollbackReport>,
+  pub retry_url: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -31,7 +50,8 @@ pub struct SuccessContext {
   pub actions_url: String,
   pub repo_nwo: GitHubNWO,
   pub service_id: String,
-  pub is_ready: bool
+  pub is_ready: bool,
+  pub active_version: Option<i32>,
 }
 
 #[derive(Serialize)]